@@ -0,0 +1,918 @@
+use byteorder::{ReadBytesExt, BigEndian};
+use flate2::read::ZlibDecoder;
+use lzma_rs::lzma_decompress;
+use math::round;
+use md5;
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::{Cursor, Seek, SeekFrom, Read, Write, BufReader};
+use std::ptr;
+
+use crypto;
+use {Error, ErrorKind, Result};
+
+
+/// The header versions we know how to read. Found on PS3 and later, they share one layout so far.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PSArcVersion {
+    V1_3,
+    V1_4
+}
+
+impl PSArcVersion {
+    pub fn from_header_value(major: u16, minor: u16) -> Result<Self> {
+        match (major, minor) {
+            (1, 3) => Ok(PSArcVersion::V1_3),
+            (1, 4) => Ok(PSArcVersion::V1_4),
+            _ => Err(Error::from(ErrorKind::UnsupportedVersion(major, minor)))
+        }
+    }
+
+    pub fn get_header_value(&self) -> (u16, u16) {
+        match self {
+            PSArcVersion::V1_3 => (1, 3),
+            PSArcVersion::V1_4 => (1, 4)
+        }
+    }
+
+    /// Size of the TOC entry fields this version defines: name digest, block index, length and offset.
+    /// Entries may be larger than this, the rest is skipped.
+    pub fn toc_entry_fields_size(&self) -> u32 {
+        match self {
+            PSArcVersion::V1_3 | PSArcVersion::V1_4 => 30
+        }
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CompressionType {
+    None,
+    ZLIB,
+    LZMA
+}
+
+impl CompressionType {
+    pub fn get_header_value(&self) -> u32 {
+        match self {
+            CompressionType::None => 0x00000000,
+            CompressionType::ZLIB => 0x7A6C6962,
+            CompressionType::LZMA => 0x6C7A6D61
+        }
+    }
+}
+
+
+bitflags! {
+    /// Archive wide flags from the header. Without any flags set paths are relative and case sensitive.
+    pub struct ArchiveFlags: u32 {
+        const IGNORE_CASE = 0x1;
+        const ABSOLUTE_PATHS = 0x2;
+        const ENCRYPTED_TOC = 0x4;
+    }
+}
+
+impl ArchiveFlags {
+    /// The MD5 digest stored in the TOC for a manifest path; ignore-case archives hash the uppercased path.
+    pub fn name_digest(&self, name: &str) -> [u8; 16] {
+        if self.contains(ArchiveFlags::IGNORE_CASE) {
+            md5::compute(name.to_uppercase()).0
+        } else {
+            md5::compute(name).0
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct FileEntry {
+    pub name: String,
+    pub name_digest: [u8; 16],
+    pub index_list_size: u32,
+    pub length: u64,
+    pub offset: u64
+}
+
+#[derive(Debug)]
+pub enum BlockSizeType {
+    U16,
+    U24,
+    U32
+}
+
+impl BlockSizeType {
+    pub fn get_bytecount(&self) -> usize {
+        match self {
+            BlockSizeType::U16 => 2,
+            BlockSizeType::U24 => 3,
+            BlockSizeType::U32 => 4
+        }
+    }
+
+    pub fn get_bitcount(&self) -> u64 {
+        match self {
+            BlockSizeType::U16 => 65536,
+            BlockSizeType::U24 => 16777216,
+            BlockSizeType::U32 => 4294967296
+        }
+    }
+
+    pub fn get_header_value(&self) -> u32 {
+        match self {
+            BlockSizeType::U16 => 65536,
+            BlockSizeType::U24 => 16777216,
+            BlockSizeType::U32 => 4294967295
+        }
+    }
+}
+
+
+#[derive(Debug)]
+pub struct PSArc {
+    pub version: PSArcVersion,
+    pub version_minor: u16,
+    pub version_major: u16,
+    pub compression_type: CompressionType,
+    pub toc_length: u32,
+    pub toc_entry_size: u32,
+    pub toc_entry_count: u32,
+    pub block_size: BlockSizeType,
+    pub archive_flags: ArchiveFlags,
+    /// The flags as stored in the header, including bits `ArchiveFlags` does not know.
+    pub archive_flags_raw: u32,
+    pub entries: Vec<FileEntry>,
+    pub block_sizes: Vec<u64>,
+    /// Manifest paths whose name digest matches no TOC entry. Their entries are left unnamed,
+    /// unless `name_by_position` pairs them up.
+    pub name_mismatches: Vec<String>,
+    /// Running total of the compressed sizes in `block_sizes`, so finding a block
+    /// does not mean adding up every block before it.
+    block_positions: Vec<u64>
+}
+
+impl PSArc {
+    pub fn open<R: Read + Seek>(file: &mut R) -> Result<Self> {
+        Self::open_with_key(file, None)
+    }
+
+    /// Like `open`, with the key to decrypt the TOC of archives flagged `ENCRYPTED_TOC`.
+    pub fn open_with_key<R: Read + Seek>(file: &mut R, key: Option<&[u8]>) -> Result<Self> {
+        let empty_string = "".to_string();
+        let mut header_data = [0; 32];
+        if let Err(e) = file.read_exact(&mut header_data) {
+            return match e.kind() {
+                io::ErrorKind::UnexpectedEof => Err(Error::from(ErrorKind::TruncatedHeader)),
+                _ => Err(Error::from(e))
+            };
+        }
+        let mut header = Cursor::new(&header_data[..]);
+        let magic = header.read_u32::<BigEndian>()?;
+        if magic != 0x50534152 {
+            return Err(Error::from("Invalid magic"));
+        }
+
+        let version_major = header.read_u16::<BigEndian>()?;
+        let version_minor = header.read_u16::<BigEndian>()?;
+        let version = PSArcVersion::from_header_value(version_major, version_minor)?;
+
+        let compression_type = match header.read_u32::<BigEndian>() {
+            Ok(value) => {
+                match value {
+                    0x00000000 => CompressionType::None,
+                    0x7A6C6962 => CompressionType::ZLIB,
+                    0x6C7A6D61 => CompressionType::LZMA,
+                    _ => {
+                        return Err(Error::from(format!("Invalid compression type {}", value)));
+                    }
+                }
+            },
+            Err(e) => { return Err(Error::from(e)); }
+        };
+
+        let toc_length = header.read_u32::<BigEndian>()?;
+        let toc_entry_size = header.read_u32::<BigEndian>()?;
+        let toc_entry_count = header.read_u32::<BigEndian>()?;
+        if toc_entry_size < version.toc_entry_fields_size() {
+            return Err(Error::from(format!("TOC entry size {} is too small, version {}.{} entries need at least {} bytes",
+                                           toc_entry_size, version_major, version_minor, version.toc_entry_fields_size())));
+        }
+        let block_size = match header.read_u32::<BigEndian>()? {
+            65536 => BlockSizeType::U16,
+            16777216 => BlockSizeType::U24,
+            4294967295 => BlockSizeType::U32,
+            i => {
+                return Err(Error::from(format!("Invalid block size type {}", i)))
+            }
+        };
+        // Unknown bits are kept in archive_flags_raw, only the known ones affect reading.
+        let archive_flags_raw = header.read_u32::<BigEndian>()?;
+        let archive_flags = ArchiveFlags::from_bits_truncate(archive_flags_raw);
+
+        // Everything after the header up to toc_length is the TOC, which may be encrypted.
+        let header_length = header_data.len() as u64;
+        if (toc_length as u64) < header_length {
+            return Err(Error::from(format!("TOC length {} is shorter than the header", toc_length)));
+        }
+        let mut toc_data = Vec::new();
+        file.take(toc_length as u64 - header_length).read_to_end(&mut toc_data)?;
+        if (toc_data.len() as u64) < toc_length as u64 - header_length {
+            return Err(Error::from(ErrorKind::TruncatedToc));
+        }
+        if archive_flags.contains(ArchiveFlags::ENCRYPTED_TOC) {
+            match key {
+                Some(key) => crypto::decrypt_toc(key, &mut toc_data)?,
+                None => { return Err(Error::from("The TOC is encrypted, a key is needed to open this archive")); }
+            }
+        }
+        if toc_entry_count as u64 * toc_entry_size as u64 > toc_data.len() as u64 {
+            return Err(Error::from(format!("{} TOC entries of {} bytes do not fit in the TOC", toc_entry_count, toc_entry_size)));
+        }
+        let mut toc = Cursor::new(toc_data);
+
+        let mut entries: Vec<FileEntry> = Vec::new();
+        for i in 0..toc_entry_count {
+            // Entries may be padded or carry extra fields past the ones we know.
+            toc.set_position(i as u64 * toc_entry_size as u64);
+            let mut name_digest: [u8; 16] = [0; 16];
+            toc.read_exact(&mut name_digest)?;
+            let index_list_size = toc.read_u32::<BigEndian>()?;
+            let length = toc.read_uint::<BigEndian>(5)?;
+            let offset = toc.read_uint::<BigEndian>(5)?;
+            entries.push(FileEntry { name: empty_string.clone(), name_digest, index_list_size, length, offset });
+        }
+
+        let current_pos = toc_entry_count as u64 * toc_entry_size as u64;
+        toc.set_position(current_pos);
+        let num_blocks: u64 = (toc.get_ref().len() as u64 - current_pos) / block_size.get_bytecount() as u64;
+
+        let mut block_sizes: Vec<u64> = Vec::new();
+        for _ in 0..num_blocks {
+            block_sizes.push(toc.read_uint::<BigEndian>(block_size.get_bytecount())?);
+        }
+
+        let mut block_positions = Vec::with_capacity(block_sizes.len() + 1);
+        let mut position = 0;
+        block_positions.push(position);
+        for size in block_sizes.iter() {
+            position += match *size {
+                0 => block_size.get_bitcount(),
+                size => size
+            };
+            block_positions.push(position);
+        }
+
+        let mut i = Self {
+            version, version_minor, version_major, compression_type,
+            toc_length, toc_entry_size, toc_entry_count,
+            block_size, archive_flags, archive_flags_raw, entries, block_sizes,
+            name_mismatches: Vec::new(), block_positions
+        };
+        i.parse_manifest(file)?;
+        Ok(i)
+    }
+
+    fn parse_manifest<R: Read + Seek>(&mut self, file: &mut R) -> Result<()> {
+        if self.entries.is_empty() {
+            return Err(Error::from("The archive has no manifest entry"));
+        }
+        let mut cursor = Cursor::new(Vec::<u8>::new());
+        self.print_file(file, &mut cursor, 0)?;
+        let mut string_data = String::new();
+        cursor.seek(SeekFrom::Start(0))?;
+        cursor.read_to_string(&mut string_data)?;
+        if string_data.lines().count() >= self.entries.len() {
+            return Err(Error::from(ErrorKind::ManifestMismatch(string_data.lines().count(), self.entries.len().saturating_sub(1))));
+        }
+        let manifest_name = if self.archive_flags.contains(ArchiveFlags::ABSOLUTE_PATHS) {
+            "/manifest.txt"
+        } else {
+            "manifest.txt"
+        };
+        self.entries[0].name = manifest_name.to_string();
+
+        // Entries are matched to manifest paths by name digest, as the TOC is not necessarily in manifest order.
+        let mut digests: HashMap<[u8; 16], usize> = HashMap::new();
+        for (i, entry) in self.entries.iter().enumerate().skip(1) {
+            digests.entry(entry.name_digest).or_insert(i);
+        }
+        for line in string_data.lines() {
+            match digests.remove(&self.archive_flags.name_digest(line)) {
+                Some(i) => self.entries[i].name = line.to_string(),
+                None => self.name_mismatches.push(line.to_string())
+            }
+        }
+        Ok(())
+    }
+
+    /// Names the entries left unnamed after the `name_mismatches` paths, in TOC and manifest order,
+    /// for archives whose name digests can't be trusted. Nothing checks that the pairs belong together.
+    pub fn name_by_position(&mut self) {
+        let mut unnamed = self.entries.iter_mut().skip(1).filter(|e| e.name.is_empty());
+        for name in self.name_mismatches.iter() {
+            if let Some(entry) = unnamed.next() {
+                entry.name = name.clone();
+            }
+        }
+    }
+
+    fn entry(&self, index: usize) -> Result<&FileEntry> {
+        match self.entries.get(index) {
+            Some(entry) => Ok(entry),
+            None => Err(Error::from(ErrorKind::NoSuchEntry(index)))
+        }
+    }
+
+    pub fn block_count(&self, entry: &FileEntry) -> u64 {
+        round::ceil(entry.length as f64 / self.block_size.get_bitcount() as f64, 0) as u64
+    }
+
+    /// Uncompressed length of the `block`th block of an entry; only the last one can be short.
+    pub fn block_length(&self, entry: &FileEntry, block: u64) -> u64 {
+        min(self.block_size.get_bitcount(), entry.length - block * self.block_size.get_bitcount())
+    }
+
+    /// Looks up the compressed size of the `block`th block of an entry.
+    /// `index_list_size` is the position of the entry's first block in the block size table.
+    /// A stored size of 0 marks a full block that was stored uncompressed.
+    pub fn compressed_block_size(&self, entry: &FileEntry, block: u64) -> Result<u64> {
+        let table_index = entry.index_list_size as u64 + block;
+        match self.block_sizes.get(table_index as usize) {
+            Some(0) => Ok(self.block_size.get_bitcount()),
+            Some(size) => Ok(*size),
+            None => Err(Error::from(ErrorKind::BadBlock(entry.offset, format!("block {} of {} is outside the block size table", block, entry.name))))
+        }
+    }
+
+    /// Offset of the `block`th block of an entry in the archive. An entry's blocks follow each other,
+    /// both in the data and in the block size table.
+    pub fn block_offset(&self, entry: &FileEntry, block: u64) -> Result<u64> {
+        let first = entry.index_list_size as usize;
+        match (self.block_positions.get(first), self.block_positions.get(first + block as usize)) {
+            (Some(start), Some(position)) => Ok(entry.offset + position - start),
+            _ => Err(Error::from(ErrorKind::BadBlock(entry.offset, format!("block {} of {} is outside the block size table", block, entry.name))))
+        }
+    }
+
+    /// Decompresses a whole entry to `out`.
+    pub fn print_file<R: Read + Seek, W: Seek + Write>(&self, file: &mut R, out: &mut W, index: usize) -> Result<()> {
+        let entry_details = self.entry(index)?;
+        let mut block_offset = entry_details.offset;
+        for block in 0..self.block_count(entry_details) {
+            let compressed_size = self.compressed_block_size(entry_details, block)?;
+            file.seek(SeekFrom::Start(block_offset))?;
+            block_offset += compressed_size;
+            self.decompress_block(file, out, compressed_size, self.block_length(entry_details, block))?;
+        }
+
+        Ok(())
+    }
+
+    /// Decompresses only the `block`th block of an entry, for random access reads.
+    pub fn read_block<R: Read + Seek, W: Seek + Write>(&self, file: &mut R, out: &mut W, index: usize, block: u64) -> Result<u64> {
+        let entry_details = self.entry(index)?;
+        // Past the last block the size table holds the next entry's blocks, so it can't catch this.
+        if block >= self.block_count(entry_details) {
+            return Err(Error::from(ErrorKind::BadBlock(entry_details.offset, format!("{} has no block {}", entry_details.name, block))));
+        }
+        let block_offset = self.block_offset(entry_details, block)?;
+        let compressed_size = self.compressed_block_size(entry_details, block)?;
+        file.seek(SeekFrom::Start(block_offset))?;
+        self.decompress_block(file, out, compressed_size, self.block_length(entry_details, block))
+    }
+
+    /// Decompresses every block of an entry, checking that the blocks lie within the archive data,
+    /// that they add up to the entry length and that the name digest matches. Returns the problems found.
+    pub fn verify_entry<R: Read + Seek>(&self, file: &mut R, index: usize, archive_length: u64) -> Vec<String> {
+        let entry_details = match self.entry(index) {
+            Ok(entry) => entry,
+            Err(e) => { return vec![e.to_string()]; }
+        };
+        let mut problems = Vec::new();
+        if index > 0 && self.archive_flags.name_digest(&entry_details.name) != entry_details.name_digest {
+            problems.push("name digest does not match".to_string());
+        }
+
+        let mut bytes_written = 0;
+        let mut block_offset = entry_details.offset;
+        for block in 0..self.block_count(entry_details) {
+            let compressed_size = match self.compressed_block_size(entry_details, block) {
+                Ok(size) => size,
+                Err(e) => {
+                    problems.push(e.to_string());
+                    return problems;
+                }
+            };
+            if block_offset < self.toc_length as u64 || block_offset + compressed_size > archive_length {
+                problems.push(format!("block {} at {}..{} lies outside the archive data", block, block_offset, block_offset + compressed_size));
+                return problems;
+            }
+
+            let mut cursor = Cursor::new(Vec::<u8>::new());
+            let result = file.seek(SeekFrom::Start(block_offset)).map_err(Error::from)
+                .and_then(|_| self.decompress_block(file, &mut cursor, compressed_size, self.block_length(entry_details, block)));
+            match result {
+                Ok(written) => bytes_written += written,
+                Err(e) => problems.push(format!("block {}: {}", block, e))
+            }
+            block_offset += compressed_size;
+        }
+
+        if bytes_written != entry_details.length {
+            problems.push(format!("decompressed to {} bytes instead of {}", bytes_written, entry_details.length));
+        }
+        problems
+    }
+
+    /// Compressed size of a whole entry, summed over its blocks.
+    pub fn compressed_length(&self, entry: &FileEntry) -> Result<u64> {
+        let mut total = 0;
+        for block in 0..self.block_count(entry) {
+            total += self.compressed_block_size(entry, block)?;
+        }
+        Ok(total)
+    }
+
+    /// Determines the codec shared by all blocks of an entry, or `None` when it mixes codecs.
+    pub fn entry_compression<R: Read + Seek>(&self, file: &mut R, index: usize) -> Result<Option<CompressionType>> {
+        let entry_details = self.entry(index)?;
+        let mut compression = None;
+        let mut block_offset = entry_details.offset;
+        for block in 0..self.block_count(entry_details) {
+            let compressed_size = self.compressed_block_size(entry_details, block)?;
+            file.seek(SeekFrom::Start(block_offset))?;
+            block_offset += compressed_size;
+            let block_compression = self.block_compression(file, compressed_size, self.block_length(entry_details, block))?;
+            match compression {
+                Some(previous) if previous != block_compression => { return Ok(None); },
+                _ => { compression = Some(block_compression); }
+            }
+        }
+        Ok(Some(compression.unwrap_or(CompressionType::None)))
+    }
+
+    /// Identifies the codec of the block at the current position of `file`, leaving the position untouched.
+    /// Blocks whose stored size equals their uncompressed size are raw, the rest
+    /// are identified by their codec header.
+    fn block_compression<R: Read + Seek>(&self, file: &mut R, compressed_size: u64, block_length: u64) -> Result<CompressionType> {
+        if compressed_size == block_length {
+            return Ok(CompressionType::None);
+        }
+        let block_start = file.stream_position()?;
+        let block_header = match file.read_u16::<BigEndian>() {
+            Ok(value) => value,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(Error::from(ErrorKind::BadBlock(block_start, "lies past the end of the archive".to_string())));
+            },
+            Err(e) => { return Err(Error::from(e)); }
+        };
+        let compression = match block_header {
+            value if value >> 8 == 0x78 && value % 31 == 0 => CompressionType::ZLIB,
+            0x5D00 => CompressionType::LZMA,
+            value => {
+                return Err(Error::from(ErrorKind::BadBlock(block_start, format!("unknown block header {:#06x}", value))));
+            }
+        };
+        file.seek(SeekFrom::Start(block_start))?;
+        Ok(compression)
+    }
+
+    /// Decompresses a single block starting at the current position of `file`.
+    fn decompress_block<R: Read + Seek, W: Seek + Write>(&self, file: &mut R, out: &mut W, compressed_size: u64, block_length: u64) -> Result<u64> {
+        let block_start = file.stream_position()?;
+        let compression = self.block_compression(file, compressed_size, block_length)?;
+        let mut datastream = file.take(compressed_size);
+        let bytes_written = match compression {
+            CompressionType::None => io::copy(&mut datastream, out)?,
+            // Decoders get to write one byte more than the block holds, enough to notice that
+            // a corrupt block does not fit without decompressing all of it.
+            CompressionType::LZMA => {
+                let mut limited = LimitedWriter { out, remaining: block_length + 1 };
+                let result = lzma_decompress(&mut BufReader::new(datastream), &mut limited);
+                match result {
+                    Err(_) if limited.remaining == 0 => block_length + 1,
+                    Err(e) => { return Err(Error::from(ErrorKind::Codec(compression, block_start, format!("{:?}", e)))); },
+                    Ok(_) => block_length + 1 - limited.remaining
+                }
+            },
+            CompressionType::ZLIB => {
+                let mut decoder = ZlibDecoder::new(datastream).take(block_length + 1);
+                match io::copy(&mut decoder, out) {
+                    Ok(bytes_written) => bytes_written,
+                    Err(e) => { return Err(Error::from(ErrorKind::Codec(compression, block_start, e.to_string()))); }
+                }
+            },
+        };
+        if bytes_written != block_length {
+            return Err(Error::from(ErrorKind::BadBlock(block_start, format!("decompressed to {} bytes instead of {}", bytes_written, block_length))));
+        }
+        Ok(bytes_written)
+    }
+}
+
+
+/// The header fields, one per line.
+impl fmt::Display for PSArc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Version:\t\t{}.{} ({:?})", self.version_major, self.version_minor, self.version)?;
+        writeln!(f, "Compression type:\t{:?}", self.compression_type)?;
+        writeln!(f, "TOC length:\t\t{}", self.toc_length)?;
+        writeln!(f, "TOC entry size:\t\t{}", self.toc_entry_size)?;
+        writeln!(f, "TOC entry count:\t{}", self.toc_entry_count)?;
+        writeln!(f, "Block size:\t\t{:?}", self.block_size)?;
+        let unknown_flags = self.archive_flags_raw & !ArchiveFlags::all().bits();
+        if unknown_flags != 0 {
+            writeln!(f, "Archive flags:\t\t{:#x} ({:?}, unknown {:#x})", self.archive_flags_raw, self.archive_flags, unknown_flags)?;
+        } else {
+            writeln!(f, "Archive flags:\t\t{:#x} ({:?})", self.archive_flags_raw, self.archive_flags)?;
+        }
+        writeln!(f, "Amount of blocks registered:\t{}", self.block_sizes.len())
+    }
+}
+
+
+/// Passes on at most `remaining` bytes to `out` and fails after that.
+struct LimitedWriter<'a, W: 'a> {
+    out: &'a mut W,
+    remaining: u64,
+}
+
+impl<'a, W: Write> Write for LimitedWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Err(io::Error::other("output limit reached"));
+        }
+        let length = min(buf.len() as u64, self.remaining) as usize;
+        let written = self.out.write(&buf[..length])?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+
+/// A parsed archive together with the source its blocks are read from, see `Archive` and `MappedArchive`.
+pub struct OpenArchive<S> {
+    pub(crate) psarc: PSArc,
+    pub(crate) source: S,
+}
+
+impl<S> OpenArchive<S> {
+    /// The parsed header and table of contents.
+    pub fn psarc(&self) -> &PSArc {
+        &self.psarc
+    }
+
+    /// All entries in TOC order. The first one is the manifest.
+    pub fn entries(&self) -> &[FileEntry] {
+        &self.psarc.entries
+    }
+
+    /// See `PSArc::name_by_position`.
+    pub fn name_by_position(&mut self) {
+        self.psarc.name_by_position();
+    }
+}
+
+
+/// An opened archive together with the source it is read from.
+/// Block reads seek around a lot, so wrap files in a `BufReader`.
+pub type Archive<R> = OpenArchive<RefCell<R>>;
+
+impl<R: Read + Seek> OpenArchive<RefCell<R>> {
+    pub fn open(reader: R) -> Result<Self> {
+        Self::open_with_key(reader, None)
+    }
+
+    /// Like `open`, with the key to decrypt the TOC of archives flagged `ENCRYPTED_TOC`.
+    pub fn open_with_key(mut reader: R, key: Option<&[u8]>) -> Result<Self> {
+        let psarc = PSArc::open_with_key(&mut reader, key)?;
+        Ok(Self {
+            psarc,
+            source: RefCell::new(reader),
+        })
+    }
+
+    /// Gives back the underlying source.
+    pub fn into_inner(self) -> R {
+        self.source.into_inner()
+    }
+
+    /// Opens an entry for reading. Only the blocks that are actually read get decompressed,
+    /// so seeking around large entries is cheap.
+    pub fn open_entry(&self, entry: &FileEntry) -> Result<EntryReader<'_, R>> {
+        match self.psarc.entries.iter().position(|e| ptr::eq(e, entry)) {
+            Some(index) => Ok(EntryReader { archive: self, index, position: 0, block: None }),
+            None => Err(Error::from(format!("{} is not an entry of this archive", entry.name)))
+        }
+    }
+}
+
+
+/// Reads the decompressed contents of one entry, see `Archive::open_entry`.
+pub struct EntryReader<'a, R: 'a> {
+    archive: &'a Archive<R>,
+    index: usize,
+    position: u64,
+    block: Option<(u64, Vec<u8>)>,
+}
+
+impl<'a, R: Read + Seek> Read for EntryReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let psarc = &self.archive.psarc;
+        let entry = &psarc.entries[self.index];
+        if self.position >= entry.length || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block = self.position / psarc.block_size.get_bitcount();
+        let is_current = match self.block {
+            Some((current, _)) => current == block,
+            None => false
+        };
+        if !is_current {
+            let mut cursor = Cursor::new(Vec::<u8>::new());
+            let mut reader = self.archive.source.borrow_mut();
+            if let Err(e) = psarc.read_block(&mut *reader, &mut cursor, self.index, block) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+            }
+            self.block = Some((block, cursor.into_inner()));
+        }
+
+        let block_data = match self.block {
+            Some((_, ref block_data)) => block_data,
+            None => { return Ok(0); }
+        };
+        let block_start = (self.position - block * psarc.block_size.get_bitcount()) as usize;
+        if block_start >= block_data.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "block is shorter than expected"));
+        }
+        let amount = min(buf.len(), block_data.len() - block_start);
+        buf[..amount].copy_from_slice(&block_data[block_start..block_start + amount]);
+        self.position += amount as u64;
+        Ok(amount)
+    }
+}
+
+impl<'a, R: Read + Seek> Seek for EntryReader<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let length = self.archive.psarc.entries[self.index].length;
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => length as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, WriteBytesExt};
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+    use lzma_rs::lzma_compress;
+
+    use std::io::{Cursor, Write};
+
+    use super::{ArchiveFlags, CompressionType, PSArc};
+    use {Error, ErrorKind, Result};
+
+    /// Builds a version 1.4 archive with every file stored raw in a single block,
+    /// so tests can break single fields of a layout they know.
+    fn fixture(entry_size: u32, manifest: &str, files: &[&[u8]]) -> Vec<u8> {
+        let mut contents = vec![manifest.as_bytes()];
+        contents.extend(files.iter().cloned());
+        let toc_length = 32 + contents.len() as u32 * entry_size + contents.len() as u32 * 2;
+
+        let mut data = Vec::new();
+        data.write_u32::<BigEndian>(0x50534152).unwrap();
+        data.write_u16::<BigEndian>(1).unwrap();
+        data.write_u16::<BigEndian>(4).unwrap();
+        data.write_u32::<BigEndian>(0x7A6C6962).unwrap();
+        data.write_u32::<BigEndian>(toc_length).unwrap();
+        data.write_u32::<BigEndian>(entry_size).unwrap();
+        data.write_u32::<BigEndian>(contents.len() as u32).unwrap();
+        data.write_u32::<BigEndian>(65536).unwrap();
+        data.write_u32::<BigEndian>(0).unwrap();
+
+        let names: Vec<&str> = manifest.lines().collect();
+        let mut offset = toc_length as u64;
+        for (i, file) in contents.iter().enumerate() {
+            let digest = match i {
+                0 => [0; 16],
+                i => ArchiveFlags::empty().name_digest(names.get(i - 1).cloned().unwrap_or(""))
+            };
+            data.extend_from_slice(&digest);
+            data.write_u32::<BigEndian>(i as u32).unwrap();
+            data.write_uint::<BigEndian>(file.len() as u64, 5).unwrap();
+            data.write_uint::<BigEndian>(offset, 5).unwrap();
+            data.extend(vec![0xAA; entry_size as usize - 30]);
+            offset += file.len() as u64;
+        }
+        for file in contents.iter() {
+            data.write_u16::<BigEndian>(file.len() as u16).unwrap();
+        }
+        for file in contents.iter() {
+            data.extend_from_slice(file);
+        }
+        data
+    }
+
+    fn open(data: &[u8]) -> Result<PSArc> {
+        PSArc::open(&mut Cursor::new(data))
+    }
+
+    fn read(psarc: &PSArc, data: &[u8], index: usize) -> Result<Vec<u8>> {
+        read_at(psarc, data, index, 0)
+    }
+
+    fn read_at(psarc: &PSArc, data: &[u8], index: usize, block: u64) -> Result<Vec<u8>> {
+        let mut out = Cursor::new(Vec::new());
+        psarc.read_block(&mut Cursor::new(data), &mut out, index, block)?;
+        Ok(out.into_inner())
+    }
+
+    #[test]
+    fn wide_entries_are_skipped() {
+        let data = fixture(38, "a.txt\nb.txt", &[b"first", b"second"]);
+        let psarc = open(&data).unwrap();
+        assert_eq!(psarc.entries[1].name, "a.txt");
+        assert_eq!(psarc.entries[2].name, "b.txt");
+        assert_eq!(read(&psarc, &data, 2).unwrap(), b"second");
+    }
+
+    /// Byte range of the name digest of TOC entry `index` in a `fixture` with 30 byte entries.
+    fn digest_range(index: usize) -> ::std::ops::Range<usize> {
+        32 + index * 30..32 + index * 30 + 16
+    }
+
+    #[test]
+    fn entries_are_named_by_digest() {
+        let mut data = fixture(30, "a.txt\nb.txt", &[b"first", b"second"]);
+        let first_digest = data[digest_range(1)].to_vec();
+        data.copy_within(digest_range(2), digest_range(1).start);
+        data[digest_range(2)].copy_from_slice(&first_digest);
+        let psarc = open(&data).unwrap();
+        assert_eq!(psarc.entries[1].name, "b.txt");
+        assert_eq!(psarc.entries[2].name, "a.txt");
+        assert_eq!(read(&psarc, &data, 1).unwrap(), b"first");
+        assert!(psarc.name_mismatches.is_empty());
+    }
+
+    #[test]
+    fn unmatched_digest_leaves_entry_unnamed() {
+        let mut data = fixture(30, "a.txt\nb.txt\nc.txt", &[b"first", b"second", b"third"]);
+        data[digest_range(2)].copy_from_slice(&[0; 16]);
+        let mut psarc = open(&data).unwrap();
+        assert_eq!(psarc.entries[1].name, "a.txt");
+        assert_eq!(psarc.entries[2].name, "");
+        assert_eq!(psarc.entries[3].name, "c.txt");
+        assert_eq!(psarc.name_mismatches, vec!["b.txt"]);
+
+        psarc.name_by_position();
+        assert_eq!(psarc.entries[2].name, "b.txt");
+        assert_eq!(psarc.entries[3].name, "c.txt");
+    }
+
+    #[test]
+    fn name_by_position_pairs_in_order() {
+        let mut data = fixture(30, "a.txt\nb.txt\nc.txt", &[b"first", b"second", b"third"]);
+        data[digest_range(1)].copy_from_slice(&[0; 16]);
+        data[digest_range(3)].copy_from_slice(&[1; 16]);
+        let mut psarc = open(&data).unwrap();
+        assert_eq!(psarc.name_mismatches, vec!["a.txt", "c.txt"]);
+        psarc.name_by_position();
+        let names: Vec<&str> = psarc.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["manifest.txt", "a.txt", "b.txt", "c.txt"]);
+    }
+
+    #[test]
+    fn entry_size_below_known_fields() {
+        let mut data = fixture(30, "a.txt", &[b"first"]);
+        data[16..20].copy_from_slice(&[0, 0, 0, 29]);
+        let e = open(&data).unwrap_err();
+        assert!(e.to_string().contains("too small"), "{}", e);
+    }
+
+    #[test]
+    fn entries_overflowing_toc() {
+        let mut data = fixture(30, "a.txt", &[b"first"]);
+        data[20..24].copy_from_slice(&[0, 0, 0, 3]);
+        let e = open(&data).unwrap_err();
+        assert!(e.to_string().contains("do not fit"), "{}", e);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut data = fixture(30, "a.txt", &[b"first"]);
+        data[4..8].copy_from_slice(&[0, 2, 0, 1]);
+        match open(&data) {
+            Err(Error(ErrorKind::UnsupportedVersion(2, 1), _)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn truncated_header() {
+        let data = fixture(30, "a.txt", &[b"first"]);
+        match open(&data[..20]) {
+            Err(Error(ErrorKind::TruncatedHeader, _)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn truncated_toc() {
+        let data = fixture(30, "a.txt", &[b"first"]);
+        match open(&data[..40]) {
+            Err(Error(ErrorKind::TruncatedToc, _)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn manifest_longer_than_toc() {
+        let data = fixture(30, "a.txt\nb.txt\nc.txt", &[b"first"]);
+        match open(&data) {
+            Err(Error(ErrorKind::ManifestMismatch(3, 1), _)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn block_outside_size_table() {
+        let mut data = fixture(30, "a.txt", &[b"first"]);
+        data[78..82].copy_from_slice(&[0, 0, 0, 100]);
+        let psarc = open(&data).unwrap();
+        match read(&psarc, &data, 1) {
+            Err(Error(ErrorKind::BadBlock(..), _)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn block_past_the_entry() {
+        let data = fixture(30, "a.txt\nb.txt", &[b"first", b"second"]);
+        let psarc = open(&data).unwrap();
+        match read_at(&psarc, &data, 1, 1) {
+            Err(Error(ErrorKind::BadBlock(..), _)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn corrupt_codec_block() {
+        let mut data = fixture(30, "a.txt", &[&[0x55; 100]]);
+        // A 50 byte zlib header followed by an invalid deflate block type.
+        data[94..96].copy_from_slice(&[0, 50]);
+        data[101..104].copy_from_slice(&[0x78, 0x9C, 0xFF]);
+        let psarc = open(&data).unwrap();
+        match read(&psarc, &data, 1) {
+            Err(Error(ErrorKind::Codec(CompressionType::ZLIB, 101, _), _)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    /// Stores `compressed` as the only file, claiming it holds 100 bytes.
+    fn oversized(compressed: &[u8]) -> Vec<u8> {
+        let mut data = fixture(30, "a.txt", &[compressed]);
+        data[82..87].copy_from_slice(&[0, 0, 0, 0, 100]);
+        data
+    }
+
+    #[test]
+    fn zlib_block_longer_than_its_length() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0x55; 100000]).unwrap();
+        let data = oversized(&encoder.finish().unwrap());
+        let psarc = open(&data).unwrap();
+        match read(&psarc, &data, 1) {
+            Err(Error(ErrorKind::BadBlock(101, _), _)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn lzma_block_longer_than_its_length() {
+        let mut compressed = Vec::new();
+        lzma_compress(&mut &[0x55; 100000][..], &mut compressed).unwrap();
+        let data = oversized(&compressed);
+        let psarc = open(&data).unwrap();
+        match read(&psarc, &data, 1) {
+            Err(Error(ErrorKind::BadBlock(101, _), _)) => {},
+            other => panic!("{:?}", other)
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use aes::{Aes128, Aes192, Aes256};
+use cfb_mode::{Decryptor, Encryptor};
+use cfb_mode::cipher::{AsyncStreamCipher, KeyIvInit};
+
+use {Error, Result};
+
+
+/// Encrypted TOCs use AES in CFB mode with 128 bit feedback and an all-zero IV.
+/// The key size picks the AES variant, the key itself always comes from the caller.
+const IV: [u8; 16] = [0; 16];
+
+
+fn invalid_key(key: &[u8]) -> Error {
+    Error::from(format!("Invalid TOC key length {}, expected 16, 24 or 32 bytes", key.len()))
+}
+
+
+pub fn decrypt_toc(key: &[u8], data: &mut [u8]) -> Result<()> {
+    match key.len() {
+        16 => Decryptor::<Aes128>::new_from_slices(key, &IV).map_err(|_| invalid_key(key))?.decrypt(data),
+        24 => Decryptor::<Aes192>::new_from_slices(key, &IV).map_err(|_| invalid_key(key))?.decrypt(data),
+        32 => Decryptor::<Aes256>::new_from_slices(key, &IV).map_err(|_| invalid_key(key))?.decrypt(data),
+        _ => { return Err(invalid_key(key)); }
+    }
+    Ok(())
+}
+
+
+pub fn encrypt_toc(key: &[u8], data: &mut [u8]) -> Result<()> {
+    match key.len() {
+        16 => Encryptor::<Aes128>::new_from_slices(key, &IV).map_err(|_| invalid_key(key))?.encrypt(data),
+        24 => Encryptor::<Aes192>::new_from_slices(key, &IV).map_err(|_| invalid_key(key))?.encrypt(data),
+        32 => Encryptor::<Aes256>::new_from_slices(key, &IV).map_err(|_| invalid_key(key))?.encrypt(data),
+        _ => { return Err(invalid_key(key)); }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt_toc, encrypt_toc};
+
+    #[test]
+    fn round_trip() {
+        let toc: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        for &length in [16, 24, 32].iter() {
+            let key: Vec<u8> = (0..length as u8).collect();
+            let mut data = toc.clone();
+            encrypt_toc(&key, &mut data).unwrap();
+            assert!(data != toc, "{} byte key left the TOC unchanged", length);
+            decrypt_toc(&key, &mut data).unwrap();
+            assert!(data == toc, "{} byte key did not round trip", length);
+        }
+    }
+
+    #[test]
+    fn invalid_key_length() {
+        for &length in [0, 15, 17, 31, 33].iter() {
+            let key = vec![0u8; length];
+            let mut data = vec![0u8; 32];
+            assert!(encrypt_toc(&key, &mut data).is_err());
+            assert!(decrypt_toc(&key, &mut data).is_err());
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Reading and writing of PSARC archives.
+//!
+//! Any `Read + Seek` source works: files, in-memory buffers, a `Slice` of a disk image
+//! or even an `EntryReader` of an archive nested in another one.
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use std::io::{BufReader, Read};
+//!
+//! let archive = psarc::Archive::open(BufReader::new(File::open("data.psarc").unwrap())).unwrap();
+//! for entry in archive.entries() {
+//!     let mut contents = Vec::new();
+//!     archive.open_entry(entry).unwrap().read_to_end(&mut contents).unwrap();
+//!     println!("{}: {} bytes", entry.name, contents.len());
+//! }
+//! ```
+
+#[macro_use] extern crate bitflags;
+#[macro_use] extern crate error_chain;
+
+extern crate aes;
+extern crate byteorder;
+extern crate cfb_mode;
+extern crate flate2;
+extern crate lzma_rs;
+extern crate math;
+extern crate memmap2;
+extern crate md5;
+extern crate tempfile;
+
+mod archive;
+mod crypto;
+mod mapped;
+mod slice;
+mod writer;
+
+pub use archive::{Archive, ArchiveFlags, BlockSizeType, CompressionType, EntryReader, FileEntry, OpenArchive, PSArc, PSArcVersion};
+pub use mapped::MappedArchive;
+pub use slice::Slice;
+pub use writer::PSArcWriter;
+
+
+error_chain!{
+    foreign_links {
+        Io(::std::io::Error);
+    }
+
+    errors {
+        UnsupportedVersion(major: u16, minor: u16) {
+            description("unsupported version")
+            display("Unsupported PSARC version {}.{}, known versions are 1.3 and 1.4", major, minor)
+        }
+        TruncatedHeader {
+            description("truncated header")
+            display("The archive ends inside its header")
+        }
+        TruncatedToc {
+            description("truncated TOC")
+            display("The archive ends inside the TOC")
+        }
+        NoSuchEntry(index: usize) {
+            description("no such entry")
+            display("There is no entry {} in the archive", index)
+        }
+        BadBlock(offset: u64, reason: String) {
+            description("bad block")
+            display("Bad block at offset {}: {}", offset, reason)
+        }
+        Codec(compression: CompressionType, offset: u64, reason: String) {
+            description("block failed to decompress")
+            display("{:?} block at offset {} failed to decompress: {}", compression, offset, reason)
+        }
+        ManifestMismatch(paths: usize, entries: usize) {
+            description("manifest does not match the TOC")
+            display("The manifest lists {} paths, but the TOC only has {} entries for them", paths, entries)
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use memmap2::Mmap;
+
+use std::fs::File;
+use std::io::Cursor;
+
+use archive::{OpenArchive, PSArc};
+use Result;
+
+
+/// An archive read through a memory map. Block reads are plain slice operations on `&self`
+/// without a shared file position, so several threads can decompress blocks at once.
+///
+/// The archive file must not be modified while it is mapped.
+pub type MappedArchive = OpenArchive<Mmap>;
+
+impl OpenArchive<Mmap> {
+    pub fn open(file: &File) -> Result<Self> {
+        Self::open_with_key(file, None)
+    }
+
+    /// Maps `file`, using `key` for a TOC flagged `ENCRYPTED_TOC`.
+    pub fn open_with_key(file: &File, key: Option<&[u8]>) -> Result<Self> {
+        let map = unsafe { Mmap::map(file)? };
+        let psarc = PSArc::open_with_key(&mut Cursor::new(&map[..]), key)?;
+        Ok(Self {
+            psarc,
+            source: map,
+        })
+    }
+
+    /// Decompresses the `block`th block of the entry at `index`.
+    pub fn read_block(&self, index: usize, block: u64) -> Result<Vec<u8>> {
+        let mut out = Cursor::new(Vec::<u8>::new());
+        self.psarc.read_block(&mut Cursor::new(&self.source[..]), &mut out, index, block)?;
+        Ok(out.into_inner())
+    }
+}
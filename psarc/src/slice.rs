@@ -0,0 +1,118 @@
+use std::cmp::min;
+use std::io;
+use std::io::{Seek, SeekFrom, Read};
+
+
+/// A window onto part of another source, for archives embedded in something larger such as a disk image.
+pub struct Slice<R> {
+    inner: R,
+    start: u64,
+    length: u64,
+    position: u64,
+}
+
+impl<R: Read + Seek> Slice<R> {
+    pub fn new(inner: R, start: u64, length: u64) -> Self {
+        Self {
+            inner,
+            start,
+            length,
+            position: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for Slice<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.length {
+            return Ok(0);
+        }
+        let amount = min(buf.len() as u64, self.length - self.position) as usize;
+        self.inner.seek(SeekFrom::Start(self.start + self.position))?;
+        let bytes_read = self.inner.read(&mut buf[..amount])?;
+        self.position += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl<R: Read + Seek> Seek for Slice<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.length as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    use archive::{Archive, ArchiveFlags, BlockSizeType, CompressionType};
+    use writer::PSArcWriter;
+    use super::Slice;
+
+    fn read_all<R: Read>(reader: &mut R) -> Vec<u8> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn reads_stay_in_the_window() {
+        let mut slice = Slice::new(Cursor::new(b"0123456789".to_vec()), 3, 4);
+        let mut buf = [0; 10];
+        assert_eq!(slice.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"3456");
+        assert_eq!(slice.read(&mut buf).unwrap(), 0);
+
+        slice.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(slice.read(&mut buf[..1]).unwrap(), 1);
+        assert_eq!(buf[0], b'3');
+        assert_eq!(read_all(&mut slice), b"456");
+    }
+
+    #[test]
+    fn seeks_are_relative_to_the_window() {
+        let mut slice = Slice::new(Cursor::new(b"0123456789".to_vec()), 3, 4);
+        assert_eq!(slice.seek(SeekFrom::End(-1)).unwrap(), 3);
+        assert_eq!(read_all(&mut slice), b"6");
+        assert_eq!(slice.seek(SeekFrom::End(0)).unwrap(), 4);
+        assert_eq!(read_all(&mut slice), b"");
+        assert_eq!(slice.seek(SeekFrom::Start(10)).unwrap(), 10);
+        assert_eq!(read_all(&mut slice), b"");
+        assert_eq!(slice.seek(SeekFrom::Current(-8)).unwrap(), 2);
+        assert_eq!(read_all(&mut slice), b"56");
+        assert!(slice.seek(SeekFrom::Current(-5)).is_err());
+        assert!(slice.seek(SeekFrom::End(-5)).is_err());
+    }
+
+    #[test]
+    fn embedded_archive() {
+        let mut writer = PSArcWriter::new(CompressionType::ZLIB, BlockSizeType::U16, ArchiveFlags::empty());
+        writer.add_file("a.txt", Cursor::new(b"first".to_vec()));
+        writer.add_file("b.txt", Cursor::new(vec![b'x'; 70000]));
+        let mut archive_data = Vec::new();
+        writer.write(&mut archive_data).unwrap();
+
+        let mut image = vec![0xAA; 1000];
+        image.extend_from_slice(&archive_data);
+        image.extend(vec![0xBB; 1000]);
+        let slice = Slice::new(Cursor::new(image), 1000, archive_data.len() as u64);
+        let archive = Archive::open(slice).unwrap();
+        assert_eq!(archive.entries()[1].name, "a.txt");
+        assert_eq!(read_all(&mut archive.open_entry(&archive.entries()[1]).unwrap()), b"first");
+        assert_eq!(read_all(&mut archive.open_entry(&archive.entries()[2]).unwrap()), vec![b'x'; 70000]);
+    }
+}
@@ -0,0 +1,552 @@
+use byteorder::{WriteBytesExt, BigEndian};
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use lzma_rs::lzma_compress;
+use tempfile;
+
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Seek, SeekFrom, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use archive::{ArchiveFlags, BlockSizeType, CompressionType, PSArcVersion};
+use crypto;
+use {Error, Result};
+
+
+enum WriterSource {
+    File(PathBuf),
+    Reader(Box<dyn Read>),
+}
+
+
+/// Builds a PSARC archive. The manifest is generated from the added paths and becomes the
+/// first entry; every block is stored raw when compressing it would not make it smaller.
+pub struct PSArcWriter {
+    compression_type: CompressionType,
+    block_size: BlockSizeType,
+    archive_flags: ArchiveFlags,
+    toc_key: Option<Vec<u8>>,
+    files: Vec<(String, WriterSource)>,
+}
+
+impl PSArcWriter {
+    pub fn new(compression_type: CompressionType, block_size: BlockSizeType, archive_flags: ArchiveFlags) -> Self {
+        Self {
+            compression_type,
+            block_size,
+            archive_flags,
+            toc_key: None,
+            files: Vec::new(),
+        }
+    }
+
+    /// Key to encrypt the TOC with, needed when the flags include `ENCRYPTED_TOC`.
+    pub fn set_toc_key(&mut self, key: &[u8]) {
+        self.toc_key = Some(key.to_vec());
+    }
+
+    /// Number of files added so far, not counting the manifest.
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn add_file<R: Read + 'static>(&mut self, name: &str, reader: R) {
+        self.files.push((name.to_string(), WriterSource::Reader(Box::new(reader))));
+    }
+
+    /// Adds every file below `root`, named by its path relative to `root`.
+    /// A `manifest.txt` in `root` itself is skipped, as the archive gets a generated one.
+    /// Symlinks to files are packed as the file they point to. Symlinks to folders are not followed,
+    /// so a link to an ancestor can't send the walk in circles.
+    ///
+    /// Returns the paths that were left out because they are neither a regular file nor a folder:
+    /// links to folders, dangling links, FIFOs, sockets and devices.
+    pub fn add_directory(&mut self, root: &Path) -> Result<Vec<PathBuf>> {
+        let mut pending = vec![root.to_path_buf()];
+        let mut found = Vec::new();
+        let mut skipped = Vec::new();
+        while let Some(dir) = pending.pop() {
+            for dir_entry in fs::read_dir(&dir)? {
+                let dir_entry = dir_entry?;
+                let path = dir_entry.path();
+                let file_type = dir_entry.file_type()?;
+                if file_type.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let is_file = if file_type.is_symlink() {
+                    fs::metadata(&path).map(|metadata| metadata.is_file()).unwrap_or(false)
+                } else {
+                    file_type.is_file()
+                };
+                if !is_file {
+                    skipped.push(path);
+                    continue;
+                }
+                let relative_path = path.strip_prefix(root).unwrap();
+                let name = match relative_path.to_str() {
+                    Some(name) => name.replace('\\', "/"),
+                    None => { return Err(Error::from(format!("{} is not valid UTF-8", path.display()))); }
+                };
+                if name != "manifest.txt" {
+                    found.push((name, path));
+                }
+            }
+        }
+
+        found.sort();
+        for (name, path) in found {
+            self.files.push((name, WriterSource::File(path)));
+        }
+        skipped.sort();
+        Ok(skipped)
+    }
+
+    pub fn write<W: io::Write>(mut self, out: &mut W) -> Result<()> {
+        let absolute_paths = self.archive_flags.contains(ArchiveFlags::ABSOLUTE_PATHS);
+        let names: Vec<String> = self.files.iter().map(|(name, _)| {
+            if absolute_paths {
+                format!("/{}", name.trim_start_matches('/'))
+            } else {
+                name.trim_start_matches('/').to_string()
+            }
+        }).collect();
+        if self.archive_flags.contains(ArchiveFlags::ENCRYPTED_TOC) && self.toc_key.is_none() {
+            return Err(Error::from("The TOC is to be encrypted, but no key was given"));
+        }
+        self.check_names(&names)?;
+
+        // Block data goes to a scratch file first, the TOC in front of it needs the block sizes.
+        let mut data = tempfile::tempfile()?;
+        let mut block_sizes: Vec<u64> = Vec::new();
+        let mut entries: Vec<([u8; 16], u32, u64, u64)> = Vec::new();
+
+        let manifest = names.join("\n").into_bytes();
+        let (length, offset) = self.write_entry(&mut Cursor::new(manifest), &mut data, &mut block_sizes)?;
+        entries.push(([0; 16], 0, length, offset));
+        let files = mem::take(&mut self.files);
+        for (i, (_, source)) in files.into_iter().enumerate() {
+            let start_index = block_sizes.len() as u32;
+            let (length, offset) = match source {
+                WriterSource::File(path) => self.write_entry(&mut File::open(path)?, &mut data, &mut block_sizes)?,
+                WriterSource::Reader(mut reader) => self.write_entry(&mut reader, &mut data, &mut block_sizes)?
+            };
+            entries.push((self.archive_flags.name_digest(&names[i]), start_index, length, offset));
+        }
+
+        let version = PSArcVersion::V1_4;
+        let toc_entry_size = version.toc_entry_fields_size() as u64;
+        let toc_length = 32 + toc_entry_size * entries.len() as u64 + self.block_size.get_bytecount() as u64 * block_sizes.len() as u64;
+        if toc_length > u32::MAX as u64 {
+            return Err(Error::from("Table of contents does not fit in the header"));
+        }
+
+        out.write_u32::<BigEndian>(0x50534152)?;
+        let (version_major, version_minor) = version.get_header_value();
+        out.write_u16::<BigEndian>(version_major)?;
+        out.write_u16::<BigEndian>(version_minor)?;
+        out.write_u32::<BigEndian>(self.compression_type.get_header_value())?;
+        out.write_u32::<BigEndian>(toc_length as u32)?;
+        out.write_u32::<BigEndian>(toc_entry_size as u32)?;
+        out.write_u32::<BigEndian>(entries.len() as u32)?;
+        out.write_u32::<BigEndian>(self.block_size.get_header_value())?;
+        out.write_u32::<BigEndian>(self.archive_flags.bits())?;
+
+        let mut toc = Vec::new();
+        for (name_digest, start_index, length, offset) in entries {
+            if length >= 1 << 40 || toc_length + offset >= 1 << 40 {
+                return Err(Error::from("Archive too large for 40 bit offsets"));
+            }
+            toc.write_all(&name_digest)?;
+            toc.write_u32::<BigEndian>(start_index)?;
+            toc.write_uint::<BigEndian>(length, 5)?;
+            toc.write_uint::<BigEndian>(toc_length + offset, 5)?;
+        }
+        for block_size in block_sizes {
+            toc.write_uint::<BigEndian>(block_size, self.block_size.get_bytecount())?;
+        }
+        if self.archive_flags.contains(ArchiveFlags::ENCRYPTED_TOC) {
+            crypto::encrypt_toc(self.toc_key.as_ref().unwrap(), &mut toc)?;
+        }
+        out.write_all(&toc)?;
+
+        data.seek(SeekFrom::Start(0))?;
+        io::copy(&mut data, out)?;
+        Ok(())
+    }
+
+    /// Rejects names the archive could not be read back with: the manifest is split into lines,
+    /// and entries are found by name digest, which is case-insensitive for `IGNORE_CASE` archives.
+    fn check_names(&self, names: &[String]) -> Result<()> {
+        let ignore_case = self.archive_flags.contains(ArchiveFlags::IGNORE_CASE);
+        let mut seen = HashSet::new();
+        for name in names.iter() {
+            if name.trim_start_matches('/').is_empty() {
+                return Err(Error::from("A file has an empty name"));
+            }
+            if name.contains(['\n', '\r']) {
+                return Err(Error::from(format!("{:?} contains a line break", name)));
+            }
+            let key = if ignore_case { name.to_uppercase() } else { name.clone() };
+            if !seen.insert(key) {
+                return Err(Error::from(format!("{} is added more than once", name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits `reader` into blocks, appending them to `data` and their sizes to `block_sizes`.
+    /// Returns the uncompressed length and the offset of the first block within `data`.
+    fn write_entry<R: Read, W: io::Write + Seek>(&self, reader: &mut R, data: &mut W, block_sizes: &mut Vec<u64>) -> Result<(u64, u64)> {
+        let offset = data.stream_position()?;
+        let mut length = 0;
+        loop {
+            let mut block = Vec::new();
+            reader.take(self.block_size.get_bitcount()).read_to_end(&mut block)?;
+            if block.is_empty() {
+                break;
+            }
+            length += block.len() as u64;
+
+            let compressed = match self.compression_type {
+                CompressionType::None => None,
+                CompressionType::ZLIB => {
+                    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+                    encoder.write_all(&block)?;
+                    Some(encoder.finish()?)
+                },
+                CompressionType::LZMA => {
+                    let mut compressed = Vec::new();
+                    lzma_compress(&mut &block[..], &mut compressed)?;
+                    Some(compressed)
+                }
+            };
+            match compressed {
+                Some(ref compressed) if compressed.len() < block.len() => {
+                    data.write_all(compressed)?;
+                    block_sizes.push(compressed.len() as u64);
+                },
+                _ => {
+                    data.write_all(&block)?;
+                    if block.len() as u64 == self.block_size.get_bitcount() {
+                        block_sizes.push(0);
+                    } else {
+                        block_sizes.push(block.len() as u64);
+                    }
+                }
+            }
+        }
+        Ok((length, offset))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tempfile;
+
+    use std::fs;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    use archive::{Archive, ArchiveFlags, BlockSizeType, CompressionType};
+    use Result;
+    use super::PSArcWriter;
+
+    /// Bytes that don't compress, so their blocks are stored raw.
+    fn noise(length: usize) -> Vec<u8> {
+        let mut state: u32 = 12345;
+        (0..length).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    fn text(length: usize) -> Vec<u8> {
+        b"hello psarc world\n".iter().cycle().take(length).cloned().collect()
+    }
+
+    /// Compressible blocks around a raw one, so the entry mixes codecs.
+    fn mixed(compressible: usize) -> Vec<u8> {
+        let mut contents = text(65536);
+        contents.extend(noise(65536));
+        contents.extend(text(compressible));
+        contents
+    }
+
+    fn test_files() -> Vec<(&'static str, Vec<u8>)> {
+        vec![
+            ("dir/text.txt", text(65536 * 2 + 100)),
+            ("dir/mixed.bin", mixed(100)),
+            ("dir/full_blocks.bin", noise(65536 * 2)),
+            ("dir/sub/short.bin", noise(65536 + 1000)),
+            ("Mixed/Case.txt", text(10)),
+            ("empty.txt", Vec::new()),
+        ]
+    }
+
+    fn write(compression_type: CompressionType, archive_flags: ArchiveFlags, files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut writer = PSArcWriter::new(compression_type, BlockSizeType::U16, archive_flags);
+        for &(name, ref contents) in files {
+            writer.add_file(name, Cursor::new(contents.clone()));
+        }
+        let mut out = Vec::new();
+        writer.write(&mut out).unwrap();
+        out
+    }
+
+    fn check_round_trip(compression_type: CompressionType, archive_flags: ArchiveFlags) {
+        let files = test_files();
+        let data = write(compression_type, archive_flags, &files);
+        let archive = Archive::open(Cursor::new(&data[..])).unwrap();
+        assert_eq!(archive.psarc().compression_type, compression_type);
+        assert_eq!(archive.psarc().archive_flags, archive_flags);
+        assert_eq!(archive.entries().len(), files.len() + 1);
+
+        let prefix = if archive_flags.contains(ArchiveFlags::ABSOLUTE_PATHS) { "/" } else { "" };
+        assert_eq!(archive.entries()[0].name, format!("{}manifest.txt", prefix));
+        for (entry, &(name, ref contents)) in archive.entries()[1..].iter().zip(files.iter()) {
+            assert_eq!(entry.name, format!("{}{}", prefix, name));
+            let mut read = Vec::new();
+            archive.open_entry(entry).unwrap().read_to_end(&mut read).unwrap();
+            assert!(read == *contents, "{} differs after the round trip", name);
+        }
+
+        for index in 0..archive.entries().len() {
+            let problems = archive.psarc().verify_entry(&mut Cursor::new(&data[..]), index, data.len() as u64);
+            assert!(problems.is_empty(), "{}: {:?}", archive.entries()[index].name, problems);
+        }
+    }
+
+    #[test]
+    fn round_trip_zlib() {
+        check_round_trip(CompressionType::ZLIB, ArchiveFlags::empty());
+    }
+
+    #[test]
+    fn round_trip_lzma() {
+        check_round_trip(CompressionType::LZMA, ArchiveFlags::empty());
+    }
+
+    #[test]
+    fn round_trip_none() {
+        check_round_trip(CompressionType::None, ArchiveFlags::empty());
+    }
+
+    #[test]
+    fn round_trip_flags() {
+        check_round_trip(CompressionType::ZLIB, ArchiveFlags::IGNORE_CASE);
+        check_round_trip(CompressionType::ZLIB, ArchiveFlags::ABSOLUTE_PATHS);
+        check_round_trip(CompressionType::LZMA, ArchiveFlags::IGNORE_CASE | ArchiveFlags::ABSOLUTE_PATHS);
+    }
+
+    #[test]
+    fn mixed_codec_entry() {
+        for &compression_type in [CompressionType::ZLIB, CompressionType::LZMA].iter() {
+            let files = vec![("mixed.bin", mixed(100)), ("text.txt", text(10000))];
+            let data = write(compression_type, ArchiveFlags::empty(), &files);
+            let archive = Archive::open(Cursor::new(&data[..])).unwrap();
+            let psarc = archive.psarc();
+            let entry = &psarc.entries[1];
+            assert_eq!(&psarc.block_sizes[entry.index_list_size as usize + 1], &0);
+            assert_eq!(psarc.entry_compression(&mut Cursor::new(&data[..]), 1).unwrap(), None);
+            assert_eq!(psarc.entry_compression(&mut Cursor::new(&data[..]), 2).unwrap(), Some(compression_type));
+
+            let mut read = Vec::new();
+            archive.open_entry(entry).unwrap().read_to_end(&mut read).unwrap();
+            assert!(read == mixed(100), "{:?} entry differs after the round trip", compression_type);
+        }
+    }
+
+    #[test]
+    fn entry_reader_seeks() {
+        for &compression_type in [CompressionType::ZLIB, CompressionType::LZMA, CompressionType::None].iter() {
+            let contents = mixed(1000);
+            let data = write(compression_type, ArchiveFlags::empty(), &[("mixed.bin", contents.clone())]);
+            let archive = Archive::open(Cursor::new(&data[..])).unwrap();
+            let mut reader = archive.open_entry(&archive.entries()[1]).unwrap();
+
+            // Across the boundary of the compressible first block and the raw second one.
+            let mut buf = [0; 20];
+            assert_eq!(reader.seek(SeekFrom::Start(65536 - 10)).unwrap(), 65536 - 10);
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &contents[65536 - 10..65536 + 10]);
+            assert_eq!(reader.stream_position().unwrap(), 65536 + 10);
+
+            // Backwards into the first block again, then into the short last one.
+            reader.seek(SeekFrom::Current(-30)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &contents[65536 - 20..65536]);
+            assert_eq!(reader.seek(SeekFrom::End(-15)).unwrap(), contents.len() as u64 - 15);
+            let mut tail = Vec::new();
+            reader.read_to_end(&mut tail).unwrap();
+            assert_eq!(&tail[..], &contents[contents.len() - 15..]);
+
+            assert_eq!(reader.seek(SeekFrom::End(10)).unwrap(), contents.len() as u64 + 10);
+            assert_eq!(reader.read(&mut buf).unwrap(), 0);
+            assert!(reader.seek(SeekFrom::End(-(contents.len() as i64) - 1)).is_err());
+        }
+    }
+
+    #[test]
+    fn full_raw_blocks_are_stored_as_zero() {
+        let files = vec![("full_blocks.bin", noise(65536 * 2)), ("short.bin", noise(100))];
+        let archive = Archive::open(Cursor::new(write(CompressionType::ZLIB, ArchiveFlags::empty(), &files))).unwrap();
+        let psarc = archive.psarc();
+        let full = &psarc.entries[1];
+        assert_eq!(psarc.block_count(full), 2);
+        assert_eq!(&psarc.block_sizes[full.index_list_size as usize..full.index_list_size as usize + 2], &[0, 0]);
+        let short = &psarc.entries[2];
+        assert_eq!(psarc.block_sizes[short.index_list_size as usize], 100);
+    }
+
+    #[test]
+    fn empty_file_has_no_blocks() {
+        let files = vec![("empty.txt", Vec::new())];
+        let archive = Archive::open(Cursor::new(write(CompressionType::ZLIB, ArchiveFlags::empty(), &files))).unwrap();
+        let entry = &archive.entries()[1];
+        assert_eq!(entry.length, 0);
+        assert_eq!(archive.psarc().block_count(entry), 0);
+        let mut read = Vec::new();
+        archive.open_entry(entry).unwrap().read_to_end(&mut read).unwrap();
+        assert!(read.is_empty());
+    }
+
+    fn write_encrypted(key: &[u8]) -> Vec<u8> {
+        let mut writer = PSArcWriter::new(CompressionType::ZLIB, BlockSizeType::U16, ArchiveFlags::ENCRYPTED_TOC);
+        writer.set_toc_key(key);
+        for (name, contents) in test_files() {
+            writer.add_file(name, Cursor::new(contents));
+        }
+        let mut out = Vec::new();
+        writer.write(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn encrypted_toc_round_trip() {
+        let key: Vec<u8> = (0..32).collect();
+        let data = write_encrypted(&key);
+        let archive = Archive::open_with_key(Cursor::new(&data[..]), Some(&key)).unwrap();
+        assert!(archive.psarc().archive_flags.contains(ArchiveFlags::ENCRYPTED_TOC));
+        assert!(archive.psarc().name_mismatches.is_empty());
+        for (entry, (name, contents)) in archive.entries()[1..].iter().zip(test_files()) {
+            assert_eq!(entry.name, name);
+            let mut read = Vec::new();
+            archive.open_entry(entry).unwrap().read_to_end(&mut read).unwrap();
+            assert!(read == contents, "{} differs after the round trip", name);
+        }
+    }
+
+    #[test]
+    fn encrypted_toc_needs_a_valid_key() {
+        let key: Vec<u8> = (0..16).collect();
+        let data = write_encrypted(&key);
+        assert!(Archive::open(Cursor::new(&data[..])).is_err());
+        assert!(Archive::open_with_key(Cursor::new(&data[..]), Some(&key[..15])).is_err());
+        assert!(Archive::open_with_key(Cursor::new(&data[..]), Some(&[0u8; 20][..])).is_err());
+
+        let mut writer = PSArcWriter::new(CompressionType::ZLIB, BlockSizeType::U16, ArchiveFlags::ENCRYPTED_TOC);
+        writer.add_file("file.txt", Cursor::new(text(10)));
+        assert!(writer.write(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn directory_round_trip() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("sub/deeper")).unwrap();
+        fs::write(root.path().join("manifest.txt"), b"stale manifest").unwrap();
+        fs::write(root.path().join("sub/manifest.txt"), b"just a file").unwrap();
+        fs::write(root.path().join("sub/deeper/noise.bin"), noise(65536 + 10)).unwrap();
+        fs::write(root.path().join("top.txt"), text(100)).unwrap();
+
+        let mut writer = PSArcWriter::new(CompressionType::ZLIB, BlockSizeType::U16, ArchiveFlags::empty());
+        assert!(writer.add_directory(root.path()).unwrap().is_empty());
+        assert_eq!(writer.file_count(), 3);
+        let mut data = Vec::new();
+        writer.write(&mut data).unwrap();
+
+        let archive = Archive::open(Cursor::new(&data[..])).unwrap();
+        let expected = vec![
+            ("sub/deeper/noise.bin", noise(65536 + 10)),
+            ("sub/manifest.txt", b"just a file".to_vec()),
+            ("top.txt", text(100)),
+        ];
+        assert_eq!(archive.entries().len(), expected.len() + 1);
+        for (entry, (name, contents)) in archive.entries()[1..].iter().zip(expected) {
+            assert_eq!(entry.name, name);
+            let mut read = Vec::new();
+            archive.open_entry(entry).unwrap().read_to_end(&mut read).unwrap();
+            assert!(read == contents, "{} differs after the round trip", name);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_regular_files_are_packed() {
+        use std::os::unix::fs::symlink;
+        use std::process::Command;
+
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("sub")).unwrap();
+        fs::write(root.path().join("sub/file.txt"), text(10)).unwrap();
+        symlink(root.path(), root.path().join("sub/loop")).unwrap();
+        symlink(root.path().join("sub/file.txt"), root.path().join("link.txt")).unwrap();
+
+        symlink(root.path().join("missing.txt"), root.path().join("dangling.txt")).unwrap();
+        let status = Command::new("mkfifo").arg(root.path().join("sub/fifo")).status().unwrap();
+        assert!(status.success());
+
+        let mut writer = PSArcWriter::new(CompressionType::ZLIB, BlockSizeType::U16, ArchiveFlags::empty());
+        let skipped = writer.add_directory(root.path()).unwrap();
+        assert_eq!(skipped, vec![root.path().join("dangling.txt"), root.path().join("sub/fifo"), root.path().join("sub/loop")]);
+        let mut data = Vec::new();
+        writer.write(&mut data).unwrap();
+
+        let archive = Archive::open(Cursor::new(&data[..])).unwrap();
+        let names: Vec<&str> = archive.entries()[1..].iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["link.txt", "sub/file.txt"]);
+        let mut read = Vec::new();
+        archive.open_entry(&archive.entries()[1]).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, text(10));
+    }
+
+    fn write_names(archive_flags: ArchiveFlags, names: &[&str]) -> Result<Vec<u8>> {
+        let mut writer = PSArcWriter::new(CompressionType::ZLIB, BlockSizeType::U16, archive_flags);
+        for &name in names {
+            writer.add_file(name, Cursor::new(text(10)));
+        }
+        let mut out = Vec::new();
+        writer.write(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn empty_names_are_rejected() {
+        assert!(write_names(ArchiveFlags::empty(), &["a.txt", ""]).is_err());
+        assert!(write_names(ArchiveFlags::ABSOLUTE_PATHS, &["/"]).is_err());
+    }
+
+    #[test]
+    fn line_breaks_are_rejected() {
+        let e = write_names(ArchiveFlags::empty(), &["a\nb.txt", "c.txt"]).unwrap_err();
+        assert!(e.to_string().contains("line break"), "{}", e);
+        assert!(write_names(ArchiveFlags::empty(), &["a\rb.txt"]).is_err());
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let e = write_names(ArchiveFlags::empty(), &["a.txt", "b.txt", "a.txt"]).unwrap_err();
+        assert!(e.to_string().contains("more than once"), "{}", e);
+        assert!(write_names(ArchiveFlags::empty(), &["a.txt", "/a.txt"]).is_err());
+        assert!(write_names(ArchiveFlags::IGNORE_CASE, &["A.txt", "a.txt"]).is_err());
+
+        let data = write_names(ArchiveFlags::empty(), &["A.txt", "a.txt"]).unwrap();
+        let archive = Archive::open(Cursor::new(&data[..])).unwrap();
+        assert!(archive.psarc().name_mismatches.is_empty());
+        assert_eq!(archive.entries()[2].name, "a.txt");
+    }
+}
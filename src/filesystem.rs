@@ -0,0 +1,676 @@
+use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory, ReplyEmpty, ReplyOpen, ReplyXattr};
+use libc::{EBADF, EIO, EISDIR, ENODATA, ENOENT, ERANGE};
+use lru::LruCache;
+use psarc::{MappedArchive, Result};
+use threadpool::ThreadPool;
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+
+pub type Inode = u64;
+
+const ROOT_INODE: Inode = 1;
+const TTL: Duration = Duration::from_secs(60);           // 1 second
+const ORIGIN_XATTR: &str = "user.psarcfs.origin";
+
+
+/// Files are identified by the position of their archive in the overlay and their entry index in it.
+enum InodeData {
+    Folder(String),
+    ArchivedFile(String, usize, usize)
+}
+
+
+/// Decoder state of one open file, so concurrent readers don't evict each other's block.
+struct FileHandle {
+    archive: usize,
+    index: usize,
+    block: Option<(u64, Arc<Vec<u8>>)>,
+}
+
+
+/// Decompressed blocks keyed by (archive, entry index, block index), shared by all handles.
+/// The least recently used blocks are dropped once `budget` bytes are exceeded.
+struct BlockCache {
+    blocks: LruCache<(usize, usize, u64), Arc<Vec<u8>>>,
+    used: usize,
+    budget: usize,
+}
+
+impl BlockCache {
+    fn new(budget: usize) -> Self {
+        Self {
+            blocks: LruCache::unbounded(),
+            used: 0,
+            budget: budget,
+        }
+    }
+
+    fn get(&mut self, archive: usize, index: usize, block: u64) -> Option<Arc<Vec<u8>>> {
+        self.blocks.get(&(archive, index, block)).cloned()
+    }
+
+    /// Blocks larger than the whole budget are not kept, rather than evicting everything else first.
+    fn insert(&mut self, archive: usize, index: usize, block: u64, data: Arc<Vec<u8>>) {
+        if data.len() > self.budget {
+            return;
+        }
+        self.used += data.len();
+        if let Some(replaced) = self.blocks.put((archive, index, block), data) {
+            self.used -= replaced.len();
+        }
+        while self.used > self.budget {
+            match self.blocks.pop_lru() {
+                Some((_, evicted)) => self.used -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+
+/// Ownership and permissions of every inode, set with the `uid=`, `gid=` and `umask=` mount options,
+/// and the timestamp they all share. `show_origin` exposes the archive of each file as an xattr.
+pub struct MountOptions {
+    pub uid: u32,
+    pub gid: u32,
+    pub umask: u32,
+    pub mtime: SystemTime,
+    pub show_origin: bool,
+}
+
+
+/// The children of a folder in manifest order, indexed by name for lookups.
+/// `lowercase_names` is only filled when names are case-insensitive.
+struct Directory {
+    parent: Inode,
+    children: Vec<Inode>,
+    names: HashMap<String, Inode>,
+    lowercase_names: HashMap<String, Inode>,
+    /// Number of children that are folders, for the link count.
+    subdirectories: u32,
+}
+
+impl Directory {
+    fn new(parent: Inode) -> Self {
+        Self {
+            parent: parent,
+            children: Vec::new(),
+            names: HashMap::new(),
+            lowercase_names: HashMap::new(),
+            subdirectories: 0,
+        }
+    }
+
+    /// Entries that share a name stay listed, but lookups find the first one.
+    fn add(&mut self, inode: Inode, name: &str, folder: bool, ignore_case: bool) {
+        self.children.push(inode);
+        if folder {
+            self.subdirectories += 1;
+        }
+        self.names.entry(name.to_string()).or_insert(inode);
+        if ignore_case {
+            self.lowercase_names.entry(name.to_lowercase()).or_insert(inode);
+        }
+    }
+
+    /// An exact match wins over one that only matches when ignoring case.
+    fn find(&self, name: &str, ignore_case: bool) -> Option<Inode> {
+        match self.names.get(name) {
+            Some(inode) => Some(inode.clone()),
+            None if ignore_case => self.lowercase_names.get(&name.to_lowercase()).cloned(),
+            None => None
+        }
+    }
+}
+
+
+/// Drops everything below a folder whose place a file from a later archive has taken.
+fn remove_folder(files: &mut HashMap<Inode, InodeData>, directories: &mut HashMap<Inode, Directory>, inode: Inode) {
+    if let Some(directory) = directories.remove(&inode) {
+        for child in directory.children {
+            files.remove(&child);
+            remove_folder(files, directories, child);
+        }
+    }
+}
+
+
+/// Reads are served from a pool of worker threads, so everything they touch is shared behind an `Arc`.
+pub struct PSArcFS {
+    archives: Vec<Arc<MappedArchive>>,
+    origins: Vec<String>,
+    files: HashMap<Inode, InodeData>,
+    directories: HashMap<Inode, Directory>,
+    cache: Arc<Mutex<BlockCache>>,
+    handles: HashMap<u64, Arc<Mutex<FileHandle>>>,
+    next_fh: u64,
+    pool: ThreadPool,
+    ignore_case: bool,
+    options: MountOptions,
+}
+
+impl PSArcFS {
+    /// Mounts `archives`, given with their file names, as one tree. Folders are merged, otherwise a later
+    /// archive wins: its file replaces an earlier file or folder at the same path, its folder an earlier file.
+    pub fn new(archives: Vec<(String, MappedArchive)>, cache_budget: usize, threads: usize, ignore_case: bool, options: MountOptions) -> Self {
+        let mut files = HashMap::new();
+        let mut directories = HashMap::new();
+        let mut folder_names: HashMap<String, Inode> = HashMap::new();
+        // The last archive with entries below each folder, which decides whether a file may replace it.
+        let mut folder_archives: HashMap<Inode, usize> = HashMap::new();
+
+        let mut inode_counter = ROOT_INODE;
+
+        files.insert(inode_counter, InodeData::Folder(".".to_string()));
+        directories.insert(inode_counter, Directory::new(ROOT_INODE));
+        folder_names.insert(".".to_string(), inode_counter);
+
+        inode_counter += 1;
+
+        for (archive_index, &(_, ref archive)) in archives.iter().enumerate() {
+            for (i, entry) in archive.entries().iter().enumerate() {
+                let mut split_path = entry.name.split('/').filter(|x| x.len() > 0).peekable();
+                let mut current_path = "".to_string();
+                let mut parent_inode = ROOT_INODE;
+                while let Some(name) = split_path.next() {
+                    // Folders that only differ in case are merged when names are case-insensitive.
+                    let path_name = if ignore_case {
+                        name.to_lowercase()
+                    } else {
+                        name.to_string()
+                    };
+                    let existing = directories[&parent_inode].find(name, ignore_case);
+                    if split_path.peek().is_some() {
+                        current_path.push_str(&path_name);
+                        current_path.push('/');
+                        if let Some(inode_id) = folder_names.get(&current_path) {
+                            parent_inode = inode_id.clone();
+                            folder_archives.insert(parent_inode, archive_index);
+                            continue;
+                        }
+                        let directory = directories.get_mut(&parent_inode).unwrap();
+                        let inode = match existing.map(|inode| (inode, files.get(&inode))) {
+                            Some((inode, Some(&InodeData::ArchivedFile(_, earlier, _)))) if earlier < archive_index => {
+                                directory.subdirectories += 1;
+                                inode
+                            },
+                            _ => {
+                                directory.add(inode_counter, name, true, ignore_case);
+                                inode_counter += 1;
+                                inode_counter - 1
+                            }
+                        };
+                        files.insert(inode, InodeData::Folder(name.to_string()));
+                        directories.insert(inode, Directory::new(parent_inode));
+                        folder_names.insert(current_path.clone(), inode);
+                        folder_archives.insert(inode, archive_index);
+                        parent_inode = inode;
+                    } else {
+                        let data = InodeData::ArchivedFile(name.to_string(), archive_index, i.clone());
+                        match existing.map(|inode| (inode, files.get(&inode))) {
+                            Some((inode, Some(&InodeData::ArchivedFile(_, earlier, _)))) if earlier < archive_index => {
+                                files.insert(inode, data);
+                            },
+                            Some((inode, Some(&InodeData::Folder(_)))) if folder_archives[&inode] < archive_index => {
+                                files.insert(inode, data);
+                                remove_folder(&mut files, &mut directories, inode);
+                                directories.get_mut(&parent_inode).unwrap().subdirectories -= 1;
+                                let prefix = format!("{}{}/", current_path, path_name);
+                                folder_names.retain(|path, _| !path.starts_with(&prefix));
+                            },
+                            _ => {
+                                directories.get_mut(&parent_inode).unwrap().add(inode_counter, name, false, ignore_case);
+                                files.insert(inode_counter, data);
+                                inode_counter += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let (origins, archives) = archives.into_iter().map(|(origin, archive)| (origin, Arc::new(archive))).unzip();
+        Self {
+            archives: archives,
+            origins: origins,
+            files: files,
+            directories: directories,
+            cache: Arc::new(Mutex::new(BlockCache::new(cache_budget))),
+            handles: HashMap::new(),
+            next_fh: 1,
+            pool: ThreadPool::new(threads),
+            ignore_case: ignore_case,
+            options: options,
+        }
+    }
+
+    /// The entries of a folder from `offset` on, each with the offset to continue after it.
+    /// Entry k is ".", ".." and then the children, and gets offset k + 1:
+    /// the offset the kernel passes back to continue once the reply buffer is full.
+    fn listing(&self, inode: Inode, offset: i64) -> Option<Vec<(i64, Inode, FileType, &str)>> {
+        let directory = self.directories.get(&inode)?;
+        let dots = vec![(inode, FileType::Directory, "."), (directory.parent, FileType::Directory, "..")];
+        let children = directory.children.iter().filter_map(|inode| match self.files.get(inode) {
+            Some(InodeData::Folder(name)) => Some((inode.clone(), FileType::Directory, name.as_str())),
+            Some(InodeData::ArchivedFile(name, _, _)) => Some((inode.clone(), FileType::RegularFile, name.as_str())),
+            None => None
+        });
+        Some(dots.into_iter().chain(children).enumerate().skip(offset as usize)
+            .map(|(i, (inode, file_type, name))| ((i + 1) as i64, inode, file_type, name))
+            .collect())
+    }
+
+    /// Attributes of an inode. Directories link to themselves, their parent and each subdirectory.
+    fn attr(&self, inode: Inode) -> Option<FileAttr> {
+        let (ftype, size, perm, nlink) = match self.files.get(&inode) {
+            Some(InodeData::Folder(_)) => (FileType::Directory, 0, 0o777, 2 + self.directories[&inode].subdirectories),
+            Some(InodeData::ArchivedFile(_, archive, index)) => (FileType::RegularFile, self.archives[archive.clone()].entries()[index.clone()].length, 0o666, 1),
+            None => { return None; }
+        };
+        Some(FileAttr {
+            ino: inode,
+            size: size,
+            blocks: (size + 511) / 512,
+            atime: self.options.mtime,
+            mtime: self.options.mtime,
+            ctime: self.options.mtime,
+            ftype: ftype,
+            perm: (perm & !self.options.umask) as u16,
+            nlink: nlink,
+            uid: self.options.uid,
+            gid: self.options.gid,
+            rdev: 0,
+        })
+    }
+}
+
+
+impl Filesystem for PSArcFS {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let directory = match self.directories.get(&parent) {
+            Some(directory) => directory,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        match directory.find(name, self.ignore_case).and_then(|inode| self.attr(inode)) {
+            Some(attrs) => reply.entry(&TTL, &attrs, 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attrs) => reply.attr(&TTL, &attrs),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        let (archive, index) = match self.files.get(&ino) {
+            Some(InodeData::ArchivedFile(_, archive, id)) => (archive.clone(), id.clone()),
+            Some(InodeData::Folder(_)) => {
+                reply.error(EISDIR);
+                return;
+            },
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, Arc::new(Mutex::new(FileHandle { archive, index, block: None })));
+        reply.opened(fh, 0);
+    }
+
+    fn release(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        self.handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn read(&mut self, _req: &Request, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        let handle = match self.handles.get(&fh) {
+            Some(handle) => handle.clone(),
+            None => {
+                reply.error(EBADF);
+                return;
+            }
+        };
+
+        let archive = self.archives[handle.lock().unwrap().archive].clone();
+        let cache = self.cache.clone();
+        self.pool.execute(move || {
+            match read_blocks(&archive, &cache, &handle, offset as u64, size as u64) {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    reply.error(EIO);
+                    eprintln!("read of inode {}, offset {}, size {} failed: {}", ino, offset, size, e);
+                }
+            }
+        });
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let listing = match self.listing(ino, offset) {
+            Some(listing) => listing,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        // fuse-rs has no readdirplus, so `ls -l` still looks up each entry.
+        for (next_offset, inode, file_type, name) in listing {
+            if reply.add(inode, next_offset, file_type, name) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        match self.files.get(&ino) {
+            Some(InodeData::ArchivedFile(_, archive, _)) if self.options.show_origin && name == OsStr::new(ORIGIN_XATTR) => {
+                reply_xattr(reply, self.origins[archive.clone()].as_bytes(), size);
+            },
+            Some(_) => reply.error(ENODATA),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        match self.files.get(&ino) {
+            Some(InodeData::ArchivedFile(..)) if self.options.show_origin => {
+                reply_xattr(reply, format!("{}\0", ORIGIN_XATTR).as_bytes(), size);
+            },
+            Some(_) => reply_xattr(reply, &[], size),
+            None => reply.error(ENOENT),
+        }
+    }
+}
+
+
+/// Reports the length of `value` when `size` is 0, as callers first ask how much room they need.
+fn reply_xattr(reply: ReplyXattr, value: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if (size as usize) < value.len() {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+
+/// Collects the requested range from the blocks covering it. Locks are only held for lookups,
+/// so other workers decompress other blocks, of this file or others, at the same time.
+fn read_blocks(archive: &MappedArchive, cache: &Mutex<BlockCache>, handle: &Mutex<FileHandle>, offset: u64, size: u64) -> Result<Vec<u8>> {
+    let (archive_index, index) = {
+        let handle = handle.lock().unwrap();
+        (handle.archive, handle.index)
+    };
+    let entry = &archive.entries()[index];
+    let block_size = archive.psarc().block_size.get_bitcount();
+    let start = min(offset, entry.length);
+    let end = min(start + size, entry.length);
+    let mut data = Vec::with_capacity((end - start) as usize);
+    let mut position = start;
+    while position < end {
+        let block = position / block_size;
+        let current = match handle.lock().unwrap().block {
+            Some((current, ref block_data)) if current == block => Some(block_data.clone()),
+            _ => None
+        };
+        let block_data = match current {
+            Some(block_data) => block_data,
+            None => {
+                let cached = cache.lock().unwrap().get(archive_index, index, block);
+                let block_data = match cached {
+                    Some(block_data) => block_data,
+                    None => {
+                        let block_data = Arc::new(archive.read_block(index, block)?);
+                        cache.lock().unwrap().insert(archive_index, index, block, block_data.clone());
+                        block_data
+                    }
+                };
+                handle.lock().unwrap().block = Some((block, block_data.clone()));
+                block_data
+            }
+        };
+
+        let block_start = position - block * block_size;
+        let block_end = min(end - block * block_size, block_data.len() as u64);
+        if block_end <= block_start {
+            break;
+        }
+        data.extend_from_slice(&block_data[block_start as usize..block_end as usize]);
+        position = block * block_size + block_end;
+    }
+    Ok(data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use psarc::{ArchiveFlags, BlockSizeType, CompressionType, MappedArchive, PSArcWriter};
+    use tempfile;
+
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+
+    use super::{BlockCache, Inode, InodeData, MountOptions, PSArcFS, ROOT_INODE};
+
+    /// Writes an archive holding `files` of the given lengths and maps it.
+    fn archive(name: &str, files: &[(&str, usize)]) -> (String, MappedArchive) {
+        let mut writer = PSArcWriter::new(CompressionType::ZLIB, BlockSizeType::U16, ArchiveFlags::empty());
+        for &(path, length) in files {
+            writer.add_file(path, Cursor::new(vec![b'x'; length]));
+        }
+        let mut file = tempfile::tempfile().unwrap();
+        writer.write(&mut file).unwrap();
+        (name.to_string(), MappedArchive::open(&file).unwrap())
+    }
+
+    fn mount(archives: Vec<(String, MappedArchive)>, ignore_case: bool) -> PSArcFS {
+        let options = MountOptions { uid: 0, gid: 0, umask: 0o022, mtime: UNIX_EPOCH, show_origin: false };
+        PSArcFS::new(archives, 1024 * 1024, 1, ignore_case, options)
+    }
+
+    fn find(fs: &PSArcFS, path: &str) -> Option<Inode> {
+        let mut inode = ROOT_INODE;
+        for name in path.split('/') {
+            inode = fs.directories.get(&inode)?.find(name, fs.ignore_case)?;
+        }
+        Some(inode)
+    }
+
+    fn children(fs: &PSArcFS, path: &str) -> Vec<String> {
+        let inode = if path.is_empty() { ROOT_INODE } else { find(fs, path).unwrap() };
+        fs.directories[&inode].children.iter().map(|child| match fs.files[child] {
+            InodeData::Folder(ref name) => format!("{}/", name),
+            InodeData::ArchivedFile(ref name, _, _) => name.clone(),
+        }).collect()
+    }
+
+    fn nlink(fs: &PSArcFS, path: &str) -> u32 {
+        let inode = if path.is_empty() { ROOT_INODE } else { find(fs, path).unwrap() };
+        fs.attr(inode).unwrap().nlink
+    }
+
+    fn size(fs: &PSArcFS, path: &str) -> u64 {
+        fs.attr(find(fs, path).unwrap()).unwrap().size
+    }
+
+    fn block(length: usize) -> Arc<Vec<u8>> {
+        Arc::new(vec![0; length])
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let mut cache = BlockCache::new(10);
+        cache.insert(0, 1, 0, block(4));
+        cache.insert(0, 1, 1, block(4));
+        assert!(cache.get(0, 1, 0).is_some());
+        cache.insert(1, 1, 0, block(4));
+        assert_eq!(cache.used, 8);
+        assert!(cache.get(0, 1, 1).is_none());
+        assert!(cache.get(0, 1, 0).is_some());
+        assert!(cache.get(1, 1, 0).is_some());
+    }
+
+    #[test]
+    fn cache_replacing_a_block_counts_it_once() {
+        let mut cache = BlockCache::new(10);
+        cache.insert(0, 1, 0, block(4));
+        cache.insert(0, 1, 1, block(4));
+        cache.insert(0, 1, 0, block(4));
+        assert_eq!(cache.used, 8);
+        cache.insert(0, 1, 0, block(6));
+        assert_eq!(cache.used, 10);
+        assert!(cache.get(0, 1, 1).is_some());
+    }
+
+    #[test]
+    fn cache_skips_blocks_over_budget() {
+        let mut cache = BlockCache::new(10);
+        cache.insert(0, 1, 0, block(4));
+        cache.insert(0, 1, 1, block(11));
+        assert_eq!(cache.used, 4);
+        assert!(cache.get(0, 1, 1).is_none());
+        assert!(cache.get(0, 1, 0).is_some());
+    }
+
+    #[test]
+    fn single_archive() {
+        let fs = mount(vec![archive("base", &[("a/b/one.txt", 1), ("a/two.txt", 2), ("c/three.txt", 3)])], false);
+        assert_eq!(children(&fs, ""), vec!["manifest.txt", "a/", "c/"]);
+        assert_eq!(children(&fs, "a"), vec!["b/", "two.txt"]);
+        assert_eq!(nlink(&fs, ""), 4);
+        assert_eq!(nlink(&fs, "a"), 3);
+        assert_eq!(nlink(&fs, "a/b"), 2);
+        assert_eq!(fs.attr(find(&fs, "a/two.txt").unwrap()).unwrap().nlink, 1);
+        assert_eq!(size(&fs, "a/b/one.txt"), 1);
+        assert_eq!(find(&fs, "A/two.txt"), None);
+        assert_eq!(find(&fs, "a/missing.txt"), None);
+    }
+
+    #[test]
+    fn later_file_replaces_file() {
+        let fs = mount(vec![
+            archive("base", &[("a/file.txt", 10), ("a/kept.txt", 1)]),
+            archive("patch", &[("a/file.txt", 20)]),
+        ], false);
+        assert_eq!(children(&fs, "a"), vec!["file.txt", "kept.txt"]);
+        assert_eq!(size(&fs, "a/file.txt"), 20);
+        assert_eq!(size(&fs, "a/kept.txt"), 1);
+        match fs.files[&find(&fs, "a/file.txt").unwrap()] {
+            InodeData::ArchivedFile(_, archive, _) => assert_eq!(archive, 1),
+            _ => panic!("a/file.txt is not a file")
+        }
+    }
+
+    #[test]
+    fn file_and_folder_replace_each_other() {
+        let fs = mount(vec![
+            archive("base", &[("d/sub/deeper/f.txt", 1), ("d/sub/g.txt", 2), ("d/kept.txt", 3)]),
+            archive("file", &[("d/sub", 5)]),
+        ], false);
+        let sub = find(&fs, "d/sub").unwrap();
+        assert_eq!(size(&fs, "d/sub"), 5);
+        assert!(!fs.directories.contains_key(&sub));
+        assert_eq!(find(&fs, "d/sub/g.txt"), None);
+        assert_eq!(children(&fs, "d"), vec!["sub", "kept.txt"]);
+        assert_eq!(nlink(&fs, "d"), 2);
+        // The root, manifest.txt, d, kept.txt and sub: the subtree below sub is gone from the inode table too.
+        assert_eq!(fs.files.len(), 5);
+        assert_eq!(fs.directories.len(), 2);
+
+        let fs = mount(vec![
+            archive("base", &[("d/sub/deeper/f.txt", 1), ("d/sub/g.txt", 2), ("d/kept.txt", 3)]),
+            archive("file", &[("d/sub", 5)]),
+            archive("folder", &[("d/sub/h.txt", 7)]),
+        ], false);
+        assert_eq!(find(&fs, "d/sub").unwrap(), sub);
+        assert_eq!(children(&fs, "d"), vec!["sub/", "kept.txt"]);
+        assert_eq!(children(&fs, "d/sub"), vec!["h.txt"]);
+        assert_eq!(size(&fs, "d/sub/h.txt"), 7);
+        assert_eq!(find(&fs, "d/sub/g.txt"), None);
+        assert_eq!(nlink(&fs, "d"), 3);
+        assert_eq!(nlink(&fs, "d/sub"), 2);
+    }
+
+    #[test]
+    fn ignore_case_folds_across_archives() {
+        let archives = || vec![
+            archive("base", &[("Dir/File.txt", 10), ("Dir/Other.txt", 1)]),
+            archive("patch", &[("dir/FILE.TXT", 20), ("DIR/new.txt", 2)]),
+        ];
+
+        let fs = mount(archives(), true);
+        assert_eq!(children(&fs, ""), vec!["manifest.txt", "Dir/"]);
+        assert_eq!(children(&fs, "dir"), vec!["FILE.TXT", "Other.txt", "new.txt"]);
+        assert_eq!(size(&fs, "DIR/file.txt"), 20);
+        assert_eq!(size(&fs, "Dir/other.TXT"), 1);
+        assert_eq!(nlink(&fs, ""), 3);
+
+        let fs = mount(archives(), false);
+        assert_eq!(children(&fs, ""), vec!["manifest.txt", "Dir/", "dir/", "DIR/"]);
+        assert_eq!(size(&fs, "Dir/File.txt"), 10);
+        assert_eq!(size(&fs, "dir/FILE.TXT"), 20);
+        assert_eq!(find(&fs, "dir/File.txt"), None);
+        assert_eq!(nlink(&fs, ""), 5);
+    }
+
+    #[test]
+    fn listing_resumes_at_every_offset() {
+        let files: Vec<String> = (0..50).map(|i| format!("dir/file{}.txt", i)).collect();
+        let paths: Vec<(&str, usize)> = files.iter().map(|name| (name.as_str(), 1)).collect();
+        let fs = mount(vec![archive("base", &paths)], false);
+        let dir = find(&fs, "dir").unwrap();
+        let full = fs.listing(dir, 0).unwrap();
+        assert_eq!(full.len(), 52);
+        assert_eq!((full[0].1, full[0].3), (dir, "."));
+        assert_eq!((full[1].1, full[1].3), (ROOT_INODE, ".."));
+
+        for offset in 0..full.len() + 2 {
+            assert_eq!(fs.listing(dir, offset as i64).unwrap(), full[offset.min(full.len())..].to_vec());
+        }
+
+        // Resuming after each entry, as the kernel does when the reply buffer fills up after it.
+        let mut seen = Vec::new();
+        let mut offset = 0;
+        while let Some(&(next_offset, inode, _, _)) = fs.listing(dir, offset).unwrap().first() {
+            seen.push(inode);
+            offset = next_offset;
+        }
+        let expected: Vec<Inode> = full.iter().map(|&(_, inode, _, _)| inode).collect();
+        assert_eq!(seen, expected);
+        assert_eq!(fs.listing(12345, 0), None);
+    }
+
+    #[test]
+    fn exact_name_wins_over_case_folded_one() {
+        let fs = mount(vec![archive("base", &[("name.txt", 1), ("NAME.txt", 2)])], true);
+        assert_eq!(size(&fs, "NAME.txt"), 2);
+        assert_eq!(size(&fs, "name.txt"), 1);
+        assert_eq!(size(&fs, "Name.txt"), 1);
+    }
+}
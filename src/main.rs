@@ -1,553 +1,433 @@
 #[macro_use] extern crate clap;
-#[macro_use] extern crate error_chain;
 
-extern crate byteorder;
-extern crate flate2;
 extern crate fuse;
-extern crate id_tree;
+extern crate glob;
 extern crate libc;
-extern crate lzma_rs;
-extern crate math;
-
-use byteorder::{ReadBytesExt, BigEndian};
-use flate2::bufread::ZlibDecoder;
-use id_tree::InsertBehavior::{AsRoot, UnderNode};
-use id_tree::{Node, NodeId, Tree, TreeBuilder};
-use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
-use libc::ENOENT;
-use lzma_rs::lzma_decompress;
-use math::round;
-
-use std::cmp::min;
-use std::collections::HashMap;
+extern crate lru;
+extern crate num_cpus;
+extern crate psarc;
+extern crate threadpool;
+#[cfg(test)] extern crate tempfile;
+
+mod filesystem;
+
+use clap::ArgMatches;
+use glob::Pattern;
+use psarc::{ArchiveFlags, BlockSizeType, CompressionType, Error, MappedArchive, PSArc, PSArcWriter, Result};
+
+use std::cmp::max;
 use std::ffi::OsStr;
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{Cursor, Seek, SeekFrom, Read, BufReader};
-use std::time::{Duration, UNIX_EPOCH};
+use std::io::{Seek, SeekFrom, Write, BufReader};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use filesystem::{MountOptions, PSArcFS};
+
+
+/// Opens an archive with the key and naming options given in `matches`.
+fn open_archive(filename: &str, matches: &ArgMatches) -> Result<(PSArc, BufReader<File>)> {
+    let key = read_key(matches)?;
+    let mut reader = BufReader::new(File::open(filename)?);
+    let mut psarc = PSArc::open_with_key(&mut reader, key.as_ref().map(|key| &key[..]))?;
+    if report_name_mismatches(filename, &psarc, matches) {
+        psarc.name_by_position();
+    }
+    Ok((psarc, reader))
+}
 
 
-error_chain!{
-    foreign_links {
-        Io(::std::io::Error);
+fn open_mapped_archive(filename: &str, matches: &ArgMatches) -> Result<MappedArchive> {
+    let key = read_key(matches)?;
+    let mut archive = MappedArchive::open_with_key(&File::open(filename)?, key.as_ref().map(|key| &key[..]))?;
+    if report_name_mismatches(filename, archive.psarc(), matches) {
+        archive.name_by_position();
     }
+    Ok(archive)
 }
 
 
-#[derive(Debug, Copy, Clone)]
-enum CompressionType {
-    None,
-    ZLIB,
-    LZMA
+/// Warns about manifest paths whose entry was not found by name digest.
+/// Returns whether those entries should be named by position instead.
+fn report_name_mismatches(filename: &str, psarc: &PSArc, matches: &ArgMatches) -> bool {
+    let by_position = matches.is_present("name_by_position");
+    for name in psarc.name_mismatches.iter() {
+        if by_position {
+            eprintln!("{}: no entry has the name digest of {}, naming it by position", filename, name);
+        } else {
+            eprintln!("{}: no entry has the name digest of {}, skipping it", filename, name);
+        }
+    }
+    by_position
 }
 
 
-#[derive(Debug)]
-enum ArchiveFlags {
-    RelativePaths,
-    IgnoreCase,
-    AbsolutePaths,
-    Unknown
+/// The TOC key given by `--key` or `--key-file`. A key file holds either hex or the raw key.
+fn read_key(matches: &ArgMatches) -> Result<Option<Vec<u8>>> {
+    if let Some(hex) = matches.value_of("key") {
+        return Ok(Some(parse_hex_key(hex)?));
+    }
+    match matches.value_of("key_file") {
+        Some(path) => {
+            let data = fs::read(path)?;
+            match str::from_utf8(&data) {
+                Ok(text) if text.trim().len() > 0 && text.trim().chars().all(|c| c.is_ascii_hexdigit()) => {
+                    Ok(Some(parse_hex_key(text.trim())?))
+                },
+                _ => Ok(Some(data))
+            }
+        },
+        None => Ok(None)
+    }
 }
 
 
-#[derive(Debug)]
-struct FileEntry {
-    name: String,
-    name_digest: [u8; 16],
-    index_list_size: u32,
-    length: u64,
-    offset: u64
+fn parse_hex_key(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::from("The key is not a valid hex string"));
+    }
+    Ok((0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect())
 }
 
-#[derive(Debug)]
-enum BlockSizeType {
-    U16,
-    U24,
-    U32
-}
 
-impl BlockSizeType {
-    fn get_bytecount(&self) -> usize {
-        match self {
-            BlockSizeType::U16 => 2,
-            BlockSizeType::U24 => 3,
-            BlockSizeType::U32 => 4
-        }
+/// Where the entry `name` goes below `outdir`. Absolute paths are taken relative to `outdir`,
+/// anything that could point elsewhere is refused. `None` for an unnamed entry.
+fn extract_target(outdir: &Path, name: &str) -> Result<Option<PathBuf>> {
+    let name = name.trim_start_matches('/');
+    if name.len() == 0 {
+        return Ok(None);
     }
-
-    fn get_bitcount(&self) -> u64 {
-        match self {
-            BlockSizeType::U16 => 65536,
-            BlockSizeType::U24 => 16777216,
-            BlockSizeType::U32 => 4294967296
-        }
+    let relative_path = Path::new(name);
+    if relative_path.components().any(|c| c != Component::Normal(c.as_os_str())) {
+        return Err(Error::from("not a plain relative path"));
     }
+    Ok(Some(outdir.join(relative_path)))
 }
 
 
-#[derive(Debug)]
-struct PSArc {
-    version_minor: u16,
-    version_major: u16,
-    compression_type: CompressionType,
-    toc_length: u32,
-    toc_entry_size: u32,
-    toc_entry_count: u32,
-    block_size: BlockSizeType,
-    archive_flags: ArchiveFlags,
-    entries: Vec<FileEntry>,
-    block_sizes: Vec<u64>
-}
-
-impl PSArc {
-    fn open(file: &mut BufReader<File>) -> Result<Self> {
-        let empty_string = "".to_string();
-        let magic = file.read_u32::<BigEndian>()?;
-        if magic != 0x50534152 {
-            return Err(Error::from("Invalid magic"));
+fn extract(matches: &ArgMatches) -> Result<()> {
+    let (psarc, mut reader) = open_archive(matches.value_of("archive").unwrap(), matches)?;
+    let outdir = Path::new(matches.value_of("outdir").unwrap());
+    let overwrite = matches.value_of("overwrite").unwrap();
+    let dry_run = matches.is_present("dry_run");
+    let mut filters = Vec::new();
+    for filter in matches.values_of("filter").into_iter().flatten() {
+        match Pattern::new(filter) {
+            Ok(pattern) => filters.push(pattern),
+            Err(e) => { return Err(Error::from(format!("Invalid filter {}: {}", filter, e))); }
         }
+    }
 
-        let version_major = file.read_u16::<BigEndian>()?;
-        let version_minor = file.read_u16::<BigEndian>()?;
-
-        let compression_type = match file.read_u32::<BigEndian>() {
-            Ok(value) => {
-                match value {
-                    0x00000000 => CompressionType::None,
-                    0x7A6C6962 => CompressionType::ZLIB,
-                    0x6C7A6D61 => CompressionType::LZMA,
-                    _ => {
-                        return Err(Error::from(format!("Invalid compression type {}", value)));
-                    }
-                }
-            },
-            Err(e) => { return Err(Error::from(e)); }
-        };
-
-        let toc_length = file.read_u32::<BigEndian>()?;
-        let toc_entry_size = file.read_u32::<BigEndian>()?;
-        let toc_entry_count = file.read_u32::<BigEndian>()?;
-        let block_size = match file.read_u32::<BigEndian>()? {
-            65536 => BlockSizeType::U16,
-            16777216 => BlockSizeType::U24,
-            4294967295 => BlockSizeType::U32,
-            i => {
-                return Err(Error::from(format!("Invalid block size type {}", i)))
+    let mut extracted = 0;
+    for (i, entry) in psarc.entries.iter().enumerate() {
+        let name = entry.name.trim_start_matches('/');
+        if filters.len() > 0 && !filters.iter().any(|f| f.matches(name)) {
+            continue;
+        }
+        let target = match extract_target(outdir, &entry.name) {
+            Ok(Some(target)) => target,
+            // Entries left unnamed by a digest mismatch, reported when opening.
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Skipping {}: {}", entry.name, e);
+                continue;
             }
         };
-        let archive_flags = match file.read_u32::<BigEndian>() {
-            // TODO: replace this with bitflags.
-            Ok(value) => {
-                match value {
-                    0 => ArchiveFlags::RelativePaths,
-                    1 => ArchiveFlags::IgnoreCase,
-                    2 => ArchiveFlags::AbsolutePaths,
-                    _ => {
-                        println!("Invalid archive flags {}", value);
-                        ArchiveFlags::Unknown
-                    }
-                }
-            },
-            Err(e) => { return Err(Error::from(e)); }
-        };
-
-        let mut entries: Vec<FileEntry> = Vec::new();
-        for _ in 0..toc_entry_count {
-            let mut name_digest: [u8; 16] = [0; 16];
-            for pos in 0..16 {
-                name_digest[pos] = file.read_u8::<>()?;
+        if target.exists() {
+            match overwrite {
+                "always" => {},
+                "never" => {
+                    eprintln!("Skipping {}: already exists", target.display());
+                    continue;
+                },
+                _ => { return Err(Error::from(format!("{} already exists", target.display()))); }
             }
-            let index_list_size = file.read_u32::<BigEndian>()?;
-            let length = file.read_uint::<BigEndian>(5)?;
-            let offset = file.read_uint::<BigEndian>(5)?;
-            entries.push(FileEntry { name: empty_string.clone(), name_digest, index_list_size, length, offset });
         }
 
-        let current_pos = file.seek(SeekFrom::Current(0))?;
-        let num_blocks: u64 = (toc_length as u64 - current_pos) / block_size.get_bytecount() as u64;
-
-        let mut block_sizes: Vec<u64> = Vec::new();
-        for _ in 0..num_blocks {
-            block_sizes.push(file.read_uint::<BigEndian>(block_size.get_bytecount())?);
+        println!("{}", name);
+        extracted += 1;
+        if dry_run {
+            continue;
         }
-
-        let mut i = Self {
-            version_minor, version_major, compression_type, 
-            toc_length, toc_entry_size, toc_entry_count,
-            block_size, archive_flags, entries, block_sizes
-        };
-        i.parse_manifest(file)?;
-        return Ok(i);
-    }
-
-    fn parse_manifest(&mut self, file: &mut BufReader<File>) -> Result<()> {
-        let mut cursor = Cursor::new(Vec::<u8>::new());
-        self.print_file(file, &mut cursor, 0, None)?;
-        let mut string_data = String::new();
-        cursor.seek(SeekFrom::Start(0))?;
-        cursor.read_to_string(&mut string_data)?;
-        let mut lines: Vec<&str> = string_data.lines().collect();
-        let manifest_name = match self.archive_flags {
-            ArchiveFlags::AbsolutePaths => "/manifest.txt",
-            _ => "manifest.txt"
-        };
-        lines.insert(0, manifest_name);
-        for (i, line) in lines.iter().enumerate() {
-            self.entries[i].name = line.to_string();
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
         }
-        Ok(())
-    }
-
-    fn print_details(&self) {
-        eprintln!("Version:\t\t{}.{}", self.version_major, self.version_minor);
-        eprintln!("Compression type:\t{:?}", self.compression_type);
-        eprintln!("TOC length:\t\t{}", self.toc_length);
-        eprintln!("TOC entry size:\t\t{}", self.toc_entry_size);
-        eprintln!("TOC entry count:\t{}", self.toc_entry_count);
-        eprintln!("Block size:\t\t{:?}", self.block_size);
-        eprintln!("Archive flags:\t\t{:?}", self.archive_flags);
-        eprintln!("Amount of blocks registered:\t{}", self.block_sizes.len());
+        let mut out = File::create(&target)?;
+        psarc.print_file(&mut reader, &mut out, i)?;
     }
 
-    fn print_file<W: std::io::Seek + io::Write>(&self, file: &mut BufReader<File>, out: &mut W, index: usize, amount: Option<u64>) -> Result<()> {
-        let entry_details = &self.entries[index];
-        let amount = match amount {
-            Some(amt) => amt,
-            _ => entry_details.length
-        };
-        let blocks = round::ceil(entry_details.length as f64 / self.block_size.get_bitcount() as f64, 0) as u64;
-        file.seek(SeekFrom::Start(entry_details.offset))?;
-
-        let compression = match file.read_u16::<BigEndian>() {
-            Ok(value) => {
-                match value {
-                    0x78da | 0x7801 => CompressionType::ZLIB,
-                    0x5D00 => CompressionType::LZMA,
-                    _ => CompressionType::None
-                }
-            },
-            Err(e) => { 
-                return Err(Error::from(e)); 
-            }
-        };
-        file.seek(SeekFrom::Start(entry_details.offset))?;
-        let mut bytes_written = 0;
-        match compression {
-            CompressionType::None => {
-                let filesize = entry_details.length;
-                let mut datastream = file.take(filesize);
-                io::copy(&mut datastream, out)?;
-            },
-            CompressionType::LZMA => {
-                for _ in 0..blocks {
-                    let mut datastream = file.take(self.block_size.get_bitcount());
-                    lzma_decompress(&mut datastream, out).unwrap();
-                    let current_pos = out.seek(SeekFrom::Current(0))?;
-                    if current_pos > amount {
-                        return Ok(());
-                    }
-                }
-            },
-            CompressionType::ZLIB => {
-                for _ in 0..blocks {
-                    let datastream = file.take(self.block_size.get_bitcount());
-                    let mut decoder = ZlibDecoder::new(datastream);
-                    bytes_written += io::copy(&mut decoder, out)?;
-                    if bytes_written > amount { 
-                        return Ok(());
-                    }
-                }
-            },
-        }
-
-        Ok(())
-    }
+    eprintln!("{} {} files", if dry_run { "Would extract" } else { "Extracted" }, extracted);
+    Ok(())
 }
 
 
-pub type Inode = u64;
-
-const ROOT_INODE: Inode = 1;
-const TTL: Duration = Duration::from_secs(60);           // 1 second
-
+/// `compressed` is `None` when the block size table does not cover the entry.
+struct ListRow<'a> {
+    name: &'a str,
+    length: u64,
+    compressed: Option<u64>,
+    offset: u64,
+    blocks: u64,
+    codec: String,
+}
 
-enum InodeData {
-    Folder(String),
-    ArchivedFile(String, usize)
+impl<'a> ListRow<'a> {
+    fn ratio(&self) -> Option<f64> {
+        if self.length == 0 {
+            return Some(1.0);
+        }
+        self.compressed.map(|compressed| compressed as f64 / self.length as f64)
+    }
 }
 
 
-struct PSArcFS {
-    psarc: PSArc,
-    reader: BufReader<File>,
-    tree: Tree<Inode>,
-    files: HashMap<Inode, InodeData>,
-    node_ids: HashMap<Inode, NodeId>,
-    cache: HashMap<Inode, [u8; 16384]>,
-    open_inode: u64,
-    open_cursor: Cursor<Vec<u8>>,
+fn csv_field(value: &str) -> String {
+    if value.contains(|c: char| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
 }
 
-impl PSArcFS {
-    fn new(psarc: PSArc, reader: BufReader<File>) -> Self {
-        let mut tree = TreeBuilder::new().with_node_capacity(10000).build();
-        let mut files = HashMap::new();
-        let mut node_ids = HashMap::new();
-        let mut folder_names: HashMap<String, Inode> = HashMap::new();
-
-        let mut inode_counter = ROOT_INODE;
-
-        files.insert(inode_counter, InodeData::Folder(".".to_string()));
-        let root_id: NodeId = tree.insert(Node::new(inode_counter), AsRoot).unwrap();
-        node_ids.insert(inode_counter, root_id);
-        folder_names.insert(".".to_string(), inode_counter);
-
-        inode_counter += 1;
-
-        for (i, entry) in psarc.entries.iter().enumerate() {
-            let mut split_path = entry.name.split('/').filter(|x| x.len() > 0).peekable();
-            let mut current_path = "".to_string();
-            let mut parent_inode = ROOT_INODE;
-            while let Some(name) = split_path.next() {
-                if split_path.peek().is_some() {
-                    current_path.push_str(name);
-                    current_path.push('/');
-                    match folder_names.get(&current_path) {
-                        Some(inode_id) => {
-                            parent_inode = inode_id.clone();
-                        },
-                        None => {
-                            let node_id = node_ids.get(&parent_inode).unwrap();
-                            files.insert(inode_counter, InodeData::Folder(name.to_string()));
-                            let root_id: NodeId = tree.insert(Node::new(inode_counter), UnderNode(node_id)).unwrap();
-                            node_ids.insert(inode_counter, root_id);
-                            folder_names.insert(current_path.clone(), inode_counter);
-                            parent_inode = inode_counter.clone();
-                            inode_counter += 1;
-                        }
-                    }
-                } else {
-                    let node_id = node_ids.get(&parent_inode).unwrap();
-                    files.insert(inode_counter, InodeData::ArchivedFile(name.to_string(), i.clone()));
-                    let root_id: NodeId = tree.insert(Node::new(inode_counter), UnderNode(node_id)).unwrap();
-                    node_ids.insert(inode_counter, root_id);
-                    parent_inode = inode_counter.clone();
-                    inode_counter += 1;
-                }
-            }
-        }
 
-        Self {
-            psarc: psarc,
-            reader: reader,
-            tree: tree,
-            files: files,
-            node_ids: node_ids,
-            cache: HashMap::new(),
-            open_inode: 0,
-            open_cursor: Cursor::new(Vec::<u8>::new()),
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
         }
     }
+    escaped.push('"');
+    escaped
 }
 
 
-impl Filesystem for PSArcFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        match self.node_ids.get(&parent) {
-            Some(node_obj) => {
-                for child in self.tree.children(node_obj).unwrap() {
-                    let inode = child.data().clone();
-                    match self.files.get(&inode) {
-                        Some(InodeData::Folder(f)) => {
-                            if name.to_str().unwrap() == f {
-                                let attrs = FileAttr {
-                                    ino: inode,
-                                    size: 0,
-                                    blocks: 0,
-                                    atime: UNIX_EPOCH,                                  // 1970-01-01 00:00:00
-                                    mtime: UNIX_EPOCH,
-                                    ctime: UNIX_EPOCH,
-                                    ftype: FileType::Directory,
-                                    perm: 0o755,
-                                    nlink: 2,
-                                    uid: 0,
-                                    gid: 0,
-                                    rdev: 0,
-                                };
-                                reply.entry(&TTL, &attrs, 0);
-                                return;
-                            }
-                        },
-                        Some(InodeData::ArchivedFile(f, index)) => {
-                            if name.to_str().unwrap() == f {
-                                let attrs = FileAttr {
-                                    ino: inode,
-                                    size: self.psarc.entries.get(index.clone()).unwrap().length,
-                                    blocks: 0,
-                                    atime: UNIX_EPOCH,                                  // 1970-01-01 00:00:00
-                                    mtime: UNIX_EPOCH,
-                                    ctime: UNIX_EPOCH,
-                                    ftype: FileType::RegularFile,
-                                    perm: 0o755,
-                                    nlink: 2,
-                                    uid: 0,
-                                    gid: 0,
-                                    rdev: 0,
-                                };
-                                reply.entry(&TTL, &attrs, 0);
-                                return;
-                            }
-                        },
-                        None => {},
-                    }
-                }
-            },
-            _ => {}
-        }
+fn list(matches: &ArgMatches) -> Result<()> {
+    let (psarc, mut reader) = open_archive(matches.value_of("archive").unwrap(), matches)?;
+    let mut rows = Vec::new();
+    // A broken entry only loses the columns it breaks, finding out why is up to `verify`.
+    for (i, entry) in psarc.entries.iter().enumerate() {
+        let codec = match psarc.entry_compression(&mut reader, i) {
+            Ok(Some(compression)) => format!("{:?}", compression),
+            Ok(None) => "Mixed".to_string(),
+            Err(e) => {
+                eprintln!("{}: {}", entry.name, e);
+                "error".to_string()
+            }
+        };
+        let compressed = match psarc.compressed_length(entry) {
+            Ok(compressed) => Some(compressed),
+            Err(e) => {
+                eprintln!("{}: {}", entry.name, e);
+                None
+            }
+        };
+        rows.push(ListRow {
+            name: &entry.name,
+            length: entry.length,
+            compressed: compressed,
+            offset: entry.offset,
+            blocks: psarc.block_count(entry),
+            codec: codec,
+        });
+    }
 
-        reply.error(ENOENT);
-    }
-
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        match self.files.get(&ino) {
-            Some(InodeData::Folder(_)) => {
-                let attrs = FileAttr {
-                    ino: ino,
-                    size: 0,
-                    blocks: 0,
-                    atime: UNIX_EPOCH,                                  // 1970-01-01 00:00:00
-                    mtime: UNIX_EPOCH,
-                    ctime: UNIX_EPOCH,
-                    ftype: FileType::Directory,
-                    perm: 0o755,
-                    nlink: 2,
-                    uid: 0,
-                    gid: 0,
-                    rdev: 0,
-                };
-                reply.attr(&TTL, &attrs);
-            },
-            Some(InodeData::ArchivedFile(_, id)) => {
-                let id = id.clone();
-                let file = &self.psarc.entries[id];
-                let attrs = FileAttr {
-                    ino: ino,
-                    size: file.length,
-                    blocks: 0,
-                    atime: UNIX_EPOCH,                                  // 1970-01-01 00:00:00
-                    mtime: UNIX_EPOCH,
-                    ctime: UNIX_EPOCH,
-                    ftype: FileType::RegularFile,
-                    perm: 0o644,
-                    nlink: 1,
-                    uid: 0,
-                    gid: 0,
-                    rdev: 0,
-                };
-                reply.attr(&TTL, &attrs);
-            },
-            _ => reply.error(ENOENT),
-        }
+    match matches.value_of("sort").unwrap() {
+        "name" => rows.sort_by(|a, b| a.name.cmp(b.name)),
+        "size" => rows.sort_by_key(|r| r.length),
+        "compressed" => rows.sort_by_key(|r| r.compressed),
+        "ratio" => rows.sort_by(|a, b| a.ratio().partial_cmp(&b.ratio()).unwrap()),
+        "offset" => rows.sort_by_key(|r| r.offset),
+        _ => {}
+    }
+    if matches.is_present("reverse") {
+        rows.reverse();
     }
 
-    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
-        print!("read called for inode {:?}, offset {:?}, size {:?}", ino, offset, size);
-        if offset == 0 {
-            if size <= 16384 {
-                match self.cache.get(&ino) {
-                    Some(cached_data) => {
-                        println!(" => served from cache");
-                        reply.data(&cached_data[..size as usize]);
-                        return;
-                    }
-                    _ => {}
-                }
+    match matches.value_of("format").unwrap() {
+        "csv" => {
+            println!("name,size,compressed,ratio,offset,blocks,codec");
+            for row in rows.iter() {
+                println!("{},{},{},{},{},{},{}", csv_field(row.name), row.length,
+                    row.compressed.map(|c| c.to_string()).unwrap_or_default(),
+                    row.ratio().map(|r| format!("{:.4}", r)).unwrap_or_default(),
+                    row.offset, row.blocks, row.codec);
+            }
+        },
+        "json" => {
+            println!("[");
+            for (i, row) in rows.iter().enumerate() {
+                println!("  {{\"name\": {}, \"size\": {}, \"compressed\": {}, \"ratio\": {}, \"offset\": {}, \"blocks\": {}, \"codec\": {}}}{}",
+                    json_string(row.name), row.length,
+                    row.compressed.map(|c| c.to_string()).unwrap_or("null".to_string()),
+                    row.ratio().map(|r| format!("{:.4}", r)).unwrap_or("null".to_string()),
+                    row.offset, row.blocks, json_string(&row.codec),
+                    if i + 1 < rows.len() { "," } else { "" });
+            }
+            println!("]");
+        },
+        _ => {
+            let name_width = rows.iter().map(|r| r.name.len()).max().unwrap_or(0).max(4);
+            println!("{:<w$}  {:>12}  {:>12}  {:>7}  {:>12}  {:>6}  {}", "Name", "Size", "Compressed", "Ratio", "Offset", "Blocks", "Codec", w = name_width);
+            for row in rows.iter() {
+                println!("{:<w$}  {:>12}  {:>12}  {:>7}  {:>12}  {:>6}  {}", row.name, row.length,
+                    row.compressed.map(|c| c.to_string()).unwrap_or("?".to_string()),
+                    row.ratio().map(|r| format!("{:.1}%", r * 100.0)).unwrap_or("?".to_string()),
+                    row.offset, row.blocks, row.codec, w = name_width);
             }
         }
+    }
+    Ok(())
+}
 
-        let file_index = match self.files.get(&ino) {
-            Some(InodeData::ArchivedFile(_, id)) => id,
-            _ => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
 
-        let mut offset_option = Some(offset as u64 + size as u64);
-        if size > 16384 {
-            offset_option = None;
+fn create(matches: &ArgMatches) -> Result<()> {
+    let compression_type = match matches.value_of("compression").unwrap() {
+        "none" => CompressionType::None,
+        "lzma" => CompressionType::LZMA,
+        _ => CompressionType::ZLIB
+    };
+    let block_size = match matches.value_of("block_size").unwrap() {
+        "16777216" => BlockSizeType::U24,
+        _ => BlockSizeType::U16
+    };
+    let mut archive_flags = ArchiveFlags::empty();
+    if matches.is_present("ignore_case") {
+        archive_flags |= ArchiveFlags::IGNORE_CASE;
+    }
+    if matches.is_present("absolute_paths") {
+        archive_flags |= ArchiveFlags::ABSOLUTE_PATHS;
+    }
+    let key = read_key(matches)?;
+    if matches.is_present("encrypt_toc") {
+        if key.is_none() {
+            return Err(Error::from("--encrypt-toc needs --key or --key-file"));
         }
+        archive_flags |= ArchiveFlags::ENCRYPTED_TOC;
+    }
 
-        if self.open_inode != ino {
-            print!(" => discarding open handles");
-            self.open_inode = ino;
-            self.open_cursor = Cursor::new(Vec::<u8>::new());
-            self.psarc.print_file(&mut self.reader, &mut self.open_cursor, file_index.clone(), offset_option).unwrap();
+    let mut writer = PSArcWriter::new(compression_type, block_size, archive_flags);
+    if let Some(ref key) = key {
+        writer.set_toc_key(key);
+    }
+    for path in writer.add_directory(Path::new(matches.value_of("directory").unwrap()))? {
+        eprintln!("Skipping {}: not a regular file", path.display());
+    }
+    eprintln!("Packing {} files", writer.file_count());
+    let mut out = io::BufWriter::new(File::create(matches.value_of("archive").unwrap())?);
+    writer.write(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+
+/// Pairs of entries whose `(start, end, entry)` data extents overlap, each pair once.
+/// Every entry's blocks are contiguous, so after sorting by start only the extents still open
+/// at the start of the next one can overlap it.
+fn overlaps(extents: &mut [(u64, u64, usize)]) -> Vec<(usize, usize)> {
+    extents.sort();
+    let mut open: Vec<(u64, usize)> = Vec::new();
+    let mut pairs = Vec::new();
+    for &(start, end, i) in extents.iter() {
+        open.retain(|&(open_end, _)| open_end > start);
+        for &(_, other) in open.iter() {
+            pairs.push((i, other));
         }
+        open.push((end, i));
+    }
+    pairs
+}
 
 
-        //self.open_cursor.seek(SeekFrom::Start(0)).unwrap();
-        let list_of_bytes = self.open_cursor.get_ref();
-        let end = min(offset as usize + size as usize, list_of_bytes.len());
-        if offset == 0 {
-            if list_of_bytes.len() >= 16384 {
-                let mut cache_arr: [u8; 16384] = [0; 16384];
-                cache_arr.copy_from_slice(&list_of_bytes[..16384]);
-                self.cache.insert(ino, cache_arr);
+fn verify(matches: &ArgMatches) -> Result<()> {
+    let (psarc, mut reader) = open_archive(matches.value_of("archive").unwrap(), matches)?;
+    let quiet = matches.is_present("quiet");
+    let archive_length = reader.seek(SeekFrom::End(0))?;
+
+    let mut problems: Vec<Vec<String>> = Vec::new();
+    let mut extents = Vec::new();
+    for (i, entry) in psarc.entries.iter().enumerate() {
+        problems.push(psarc.verify_entry(&mut reader, i, archive_length));
+        if let Ok(compressed) = psarc.compressed_length(entry) {
+            if compressed > 0 {
+                extents.push((entry.offset, entry.offset + compressed, i));
             }
         }
-        reply.data(&list_of_bytes[offset as usize..end]);
-        if offset_option.is_some() {
-            self.open_inode = 0;
-        }
-        println!(" => served from archive");
     }
 
-    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        let dir_node = match self.node_ids.get(&ino) {
-            Some(node_id) => node_id,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    for (i, other) in overlaps(&mut extents) {
+        problems[i].push(format!("overlaps the data of {}", psarc.entries[other].name));
+        problems[other].push(format!("overlaps the data of {}", psarc.entries[i].name));
+    }
 
-        if offset == 0 {
-            reply.add(ino, 1, FileType::Directory, ".");
+    let mut failed = 0;
+    for (entry, entry_problems) in psarc.entries.iter().zip(problems.iter()) {
+        if entry_problems.len() > 0 {
+            failed += 1;
+            println!("FAIL\t{}: {}", entry.name, entry_problems.join("; "));
+        } else if !quiet {
+            println!("OK\t{}", entry.name);
         }
+    }
+    // With --name-by-position, mismatched paths that got an entry were checked along with it.
+    let unmatched: Vec<&String> = psarc.name_mismatches.iter().filter(|name| !psarc.entries.iter().any(|e| e.name == **name)).collect();
+    for name in unmatched.iter() {
+        println!("FAIL\t{}: no entry has its name digest", name);
+    }
 
-        match self.tree.ancestors(dir_node).unwrap().next() {
-            Some(x) => {
-                match self.files.get(x.data()) {
-                    Some(InodeData::Folder(_)) => {
-                        if offset < 2 {
-                            reply.add(x.data().clone(), 2, FileType::Directory, "..");
-                        }
-                    }
-                    _ => {}
-                };
+    if failed > 0 || !unmatched.is_empty() {
+        return Err(Error::from(format!("{} of {} entries failed verification, {} manifest paths have no entry",
+                                       failed, psarc.entries.len(), unmatched.len())));
+    }
+    eprintln!("All {} entries passed verification", psarc.entries.len());
+    Ok(())
+}
+
+
+/// Splits `-o` values into the options handled here and the ones passed on to FUSE.
+/// Files belong to the mounting user by default.
+fn parse_mount_options(values: Vec<&str>, mtime: SystemTime, show_origin: bool) -> Result<(MountOptions, Vec<String>)> {
+    let mut options = MountOptions {
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        umask: 0o022,
+        mtime: mtime,
+        show_origin: show_origin,
+    };
+    let mut fuse_options = Vec::new();
+    for option in values.iter().flat_map(|value| value.split(',')) {
+        let mut parts = option.splitn(2, '=');
+        match (parts.next().unwrap(), parts.next()) {
+            ("uid", Some(value)) => {
+                options.uid = value.parse().map_err(|_| Error::from(format!("Invalid uid {}", value)))?;
             },
-            None => {
-                if offset < 2 {
-                    reply.add(1, 2, FileType::Directory, "..");
-                }
-            }
-        };
-        for (i, child) in self.tree.children(dir_node).unwrap().enumerate().skip(offset as usize) {
-            let inode = child.data().clone();
-            match self.files.get(&inode) {
-                Some(InodeData::Folder(f)) => {
-                    reply.add(inode, (i + 2) as i64, FileType::Directory, f);
-                },
-                Some(InodeData::ArchivedFile(f, _)) => {
-                    reply.add(inode, (i + 2) as i64, FileType::RegularFile, f);
-                },
-                None => {},
-            }
+            ("gid", Some(value)) => {
+                options.gid = value.parse().map_err(|_| Error::from(format!("Invalid gid {}", value)))?;
+            },
+            ("umask", Some(value)) => {
+                options.umask = u32::from_str_radix(value, 8).map_err(|_| Error::from(format!("Invalid umask {}, expected an octal number", value)))?;
+            },
+            ("", None) => {},
+            _ => fuse_options.push(option.to_string())
         }
+    }
+    Ok((options, fuse_options))
+}
 
-        reply.ok();
+
+/// Validates arguments that count something and cannot be zero.
+fn is_positive(value: String) -> std::result::Result<(), String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err(String::from("must be at least 1")),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string())
     }
 }
 
@@ -556,35 +436,172 @@ fn main() {
     let matches = clap_app!(myapp => 
         (version: "0.1")
         (about: "Extracts PSARC files")
+        (@setting SubcommandsNegateReqs)
         (@arg file: +required "The file to extract")
         (@arg mountpoint: "Place to mount archive via FUSE")
+        (@arg cache_size: --("cache-size") +takes_value default_value("64") "Memory budget for decompressed blocks, in MiB")
+        (@arg threads: --threads +takes_value {is_positive} "Number of threads decompressing reads, defaults to the number of CPUs")
+        (@arg ignore_case: --("ignore-case") +takes_value possible_values(&["auto", "on", "off"]) default_value("auto") "Match names case-insensitively, auto does when any of the archives is flagged so")
+        (@arg overlay: --overlay +takes_value +multiple number_of_values(1) "Archive to mount over the first one, replacing its files at the same paths; may be repeated, later ones win")
+        (@arg show_origin: --("show-origin") "Name the archive each file comes from in the user.psarcfs.origin xattr")
+        (@arg options: -o +takes_value +multiple number_of_values(1) "Comma separated mount options; uid=, gid= and umask= set the owner and permissions of all files, the rest is passed to FUSE")
+        (@arg mtime: --mtime +takes_value "Modification time of all files, in seconds since the Unix epoch; defaults to that of the archive")
+        (@arg name_by_position: --("name-by-position") +global "Name entries whose digest matches no manifest path in manifest order, for archives with unreliable digests")
+        (@arg key: --key +takes_value +global conflicts_with[key_file] "Hex encoded AES key for archives with an encrypted TOC")
+        (@arg key_file: --("key-file") +takes_value +global "File holding the AES key for an encrypted TOC, as hex or raw bytes")
+        (@subcommand extract =>
+            (about: "Unpacks the archive to a directory")
+            (@arg archive: +required "The archive to unpack")
+            (@arg outdir: +required "Directory to unpack into")
+            (@arg filter: -f --filter +takes_value +multiple number_of_values(1) "Only extract paths matching this glob, may be repeated")
+            (@arg overwrite: --overwrite +takes_value possible_values(&["never", "always", "error"]) default_value("error") "What to do when a file already exists")
+            (@arg dry_run: -n --("dry-run") "Only list the files that would be extracted")
+        )
+        (@subcommand list =>
+            (about: "Lists the files in the archive with their sizes and compression")
+            (@arg archive: +required "The archive to list")
+            (@arg sort: -s --sort +takes_value possible_values(&["archive", "name", "size", "compressed", "ratio", "offset"]) default_value("archive") "Column to sort by")
+            (@arg reverse: -r --reverse "Reverse the sort order")
+            (@arg format: --format +takes_value possible_values(&["table", "csv", "json"]) default_value("table") "Output format")
+        )
+        (@subcommand verify =>
+            (about: "Checks the integrity of every file in the archive, exits non-zero on failure")
+            (@arg archive: +required "The archive to check")
+            (@arg quiet: -q --quiet "Only print the files that fail")
+        )
+        (@subcommand create =>
+            (about: "Packs a directory into a new archive")
+            (@arg directory: +required "The directory to pack")
+            (@arg archive: +required "The archive to write")
+            (@arg compression: -c --compression +takes_value possible_values(&["zlib", "lzma", "none"]) default_value("zlib") "Codec for the blocks")
+            (@arg block_size: --("block-size") +takes_value possible_values(&["65536", "16777216"]) default_value("65536") "Uncompressed size of a block")
+            (@arg ignore_case: --("ignore-case") "Mark the paths as case insensitive")
+            (@arg absolute_paths: --("absolute-paths") "Store the paths as absolute paths")
+            (@arg encrypt_toc: --("encrypt-toc") "Encrypt the TOC with the key given by --key or --key-file")
+        )
     ).get_matches();
 
-    let filename = matches.value_of("file").unwrap();
-    let file_obj = match File::open(filename) {
-        Ok(file) => file,
-        Err(e) => panic!(e)
-    };
-    let mut reader = BufReader::new(file_obj);
-    let psarc = match PSArc::open(&mut reader) {
-        Ok(psarc) => psarc,
-        Err(e) => panic!("{:?}", e)
+    let result = match matches.subcommand() {
+        ("extract", Some(sub_matches)) => Some(extract(sub_matches)),
+        ("list", Some(sub_matches)) => Some(list(sub_matches)),
+        ("verify", Some(sub_matches)) => Some(verify(sub_matches)),
+        ("create", Some(sub_matches)) => Some(create(sub_matches)),
+        _ => None
     };
-    psarc.print_details();
-    
+    if let Some(result) = result {
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    // Overlays are stacked on the archive in the given order, each replacing files of the ones below.
+    let filename = matches.value_of("file").unwrap();
+    let mut filenames = vec![filename];
+    filenames.extend(matches.values_of("overlay").map(|values| values.collect()).unwrap_or(Vec::new()));
+    let mut archives = Vec::new();
+    for filename in filenames.iter() {
+        match open_mapped_archive(filename, &matches) {
+            Ok(archive) => {
+                if filenames.len() > 1 {
+                    eprintln!("{}:", filename);
+                }
+                eprint!("{}", archive.psarc());
+                archives.push((filename.to_string(), archive));
+            },
+            Err(e) => {
+                eprintln!("{}: {}", filename, e);
+                process::exit(1);
+            }
+        }
+    }
+
     match matches.value_of("mountpoint") {
         Some(mountpoint) => {
-            let psarcfs = PSArcFS::new(psarc, reader);
+            let cache_size = value_t!(matches, "cache_size", usize).unwrap_or_else(|e| e.exit());
+            let threads = match matches.value_of("threads") {
+                Some(_) => value_t!(matches, "threads", usize).unwrap_or_else(|e| e.exit()),
+                None => num_cpus::get()
+            };
+            let ignore_case = match matches.value_of("ignore_case").unwrap() {
+                "on" => true,
+                "off" => false,
+                _ => archives.iter().any(|&(_, ref archive)| archive.psarc().archive_flags.contains(ArchiveFlags::IGNORE_CASE))
+            };
+            // Entries only change along with the archives, so they share the latest modification time.
+            let mtime = match matches.value_of("mtime") {
+                Some(_) => UNIX_EPOCH + Duration::from_secs(value_t!(matches, "mtime", u64).unwrap_or_else(|e| e.exit())),
+                None => {
+                    let mut latest = UNIX_EPOCH;
+                    for filename in filenames.iter() {
+                        match fs::metadata(filename).and_then(|metadata| metadata.modified()) {
+                            Ok(mtime) => latest = max(latest, mtime),
+                            Err(e) => {
+                                eprintln!("{}: {}", filename, e);
+                                process::exit(1);
+                            }
+                        }
+                    }
+                    latest
+                }
+            };
+            let show_origin = matches.is_present("show_origin");
+            let (mount_options, extra_options) = match parse_mount_options(matches.values_of("options").map(|values| values.collect()).unwrap_or(Vec::new()), mtime, show_origin) {
+                Ok(options) => options,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+            let psarcfs = PSArcFS::new(archives, cache_size * 1024 * 1024, threads, ignore_case, mount_options);
             let fsname = format!("fsname={}", filename);
-            let raw_options = ["-o", "ro", "-o", &fsname, "-o", "auto_unmount", "-o", "subtype=psarc", "-o", "auto_cache", "-o", "direct_io"];
+            let mut raw_options = vec!["-o", "ro", "-o", &fsname, "-o", "auto_unmount", "-o", "subtype=psarc", "-o", "auto_cache", "-o", "direct_io"];
+            for option in extra_options.iter() {
+                raw_options.push("-o");
+                raw_options.push(option);
+            }
             let options = raw_options.iter().map(|o| o.as_ref()).collect::<Vec<&OsStr>>();
 
             match fuse::mount(psarcfs, &mountpoint.to_string(), &options) {
                 Ok(_) => { println!("all ok!"); },
-                Err(e) => { println!("{:?}", e); }
+                Err(e) => {
+                    eprintln!("{}: {}", mountpoint, e);
+                    process::exit(1);
+                }
             }
 
         },
         _ => {},
     };
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{extract_target, overlaps};
+
+    #[test]
+    fn extract_targets_stay_below_outdir() {
+        let outdir = Path::new("/tmp/out");
+        assert_eq!(extract_target(outdir, "a/b.txt").unwrap(), Some(outdir.join("a/b.txt")));
+        assert_eq!(extract_target(outdir, "/abs/c.txt").unwrap(), Some(outdir.join("abs/c.txt")));
+        assert_eq!(extract_target(outdir, "").unwrap(), None);
+        assert_eq!(extract_target(outdir, "/").unwrap(), None);
+        assert!(extract_target(outdir, "../x").is_err());
+        assert!(extract_target(outdir, "a/../../x").is_err());
+        assert!(extract_target(outdir, "./a").is_err());
+    }
+
+    #[test]
+    fn every_overlap_is_reported() {
+        // 0 covers 1 and 2, which overlap each other too; 3 only touches the end of 0.
+        let mut extents = vec![(100, 110, 3), (0, 100, 0), (10, 20, 1), (15, 30, 2), (200, 210, 4)];
+        let mut pairs = overlaps(&mut extents);
+        pairs.sort();
+        assert_eq!(pairs, vec![(1, 0), (2, 0), (2, 1)]);
+        assert!(overlaps(&mut [(0, 10, 0), (10, 20, 1)]).is_empty());
+    }
+}